@@ -0,0 +1,334 @@
+use crate::computation::{compute_val_for_tree, compute_vector_for_tree};
+use crate::data_structures::{plug_into_context, GrammarType, WeightedTreeAutomatonMatrix, CONTEXT_HOLE};
+use crate::equivalence::find_counterexample;
+use crate::minimisation::next_tuple;
+use nalgebra::DMatrix;
+use std::collections::BTreeMap;
+use log::{debug, info};
+use trees::{Node, Tree};
+
+type DynamicMatrix = DMatrix<f64>;
+
+// All singular values below eps are considered equal to 0 when used in numeric algorithms.
+const EPS: f64 = 0.00001;
+
+/*
+    Exact learning of a minimal automaton in the minimal adequate teacher model (an L*-style algorithm).
+    The learner keeps a set of trees T and a set of contexts C (which always contains □) and the observation table
+    H[t, c] = f(c[t]) that is filled with membership queries. The basis B ⊆ T consists of trees whose rows are
+    linearly independent. The table is closed if the row of every σ(b_1, ..., b_k) with b_i ∈ B lies in the span
+    of the rows of B. The hypothesis of a closed table has one state per basis tree with μ(b_i) = e_i, the rows
+    of μ_σ are the coefficients of σ(b_1, ..., b_k) and γ_i = f(b_i).
+
+    If the teacher answers an equivalence query with a counterexample z, there is a lowest subtree
+    s = σ(s_1, ..., s_k) of z whose hypothesis vector α(s) doesn't satisfy α(s) H_B = H[s, C], while all s_j do.
+    Replacing s_1, ..., s_j one after another by their expansion in the basis we find a position j and contexts
+    c[σ(b_1, ..., b_{j-1}, □, s_{j+1}, ..., s_k)] which separate the row of s_j from α(s_j) H_B. Adding them to C
+    and s_j to T increases the rank of the table, so we need at most one counterexample per state.
+*/
+
+// The minimal adequate teacher answers membership queries (the weight of a tree) and equivalence queries
+// (a tree on which the hypothesis is wrong, or None if it is correct).
+pub trait Teacher {
+    fn membership_query(&self, tree: &Tree<String>) -> f64;
+    fn equivalence_query(&self, hypothesis: &WeightedTreeAutomatonMatrix) -> Option<Tree<String>>;
+}
+
+// A teacher that knows an automaton for the tree series.
+pub struct AutomatonTeacher {
+    pub automaton: WeightedTreeAutomatonMatrix,
+}
+
+impl Teacher for AutomatonTeacher {
+    fn membership_query(&self, tree: &Tree<String>) -> f64 {
+        return compute_val_for_tree(self.automaton.clone(), tree.clone());
+    }
+
+    fn equivalence_query(&self, hypothesis: &WeightedTreeAutomatonMatrix) -> Option<Tree<String>> {
+        return find_counterexample(&self.automaton, hypothesis);
+    }
+}
+
+// The observation table together with a cache for the membership queries.
+struct ObservationTable<'a> {
+    teacher: &'a dyn Teacher,
+    ranked_alphabet: BTreeMap<String, usize>,
+    trees: Vec<Tree<String>>,
+    contexts: Vec<Tree<String>>,
+    basis: Vec<Tree<String>>,
+    // The rows of the basis trees, this matrix always has full row rank.
+    h_b: DynamicMatrix,
+    cache: BTreeMap<Tree<String>, f64>,
+}
+
+impl<'a> ObservationTable<'a> {
+    fn weight(&mut self, tree: &Tree<String>) -> f64 {
+        if let Some(weight) = self.cache.get(tree) {
+            return *weight;
+        }
+        let weight = self.teacher.membership_query(tree);
+        self.cache.insert(tree.clone(), weight);
+        return weight;
+    }
+
+    fn row(&mut self, tree: &Tree<String>) -> DynamicMatrix {
+        let contexts = self.contexts.clone();
+        let mut row = DynamicMatrix::from_element(1, contexts.len(), 0.0);
+        for (column_num, context) in contexts.iter().enumerate() {
+            row[(0, column_num)] = self.weight(&plug_into_context(context, tree));
+        }
+        return row;
+    }
+
+    // Adds the tree to the basis if its row is linearly independent from the rows of the basis.
+    fn try_add_to_basis(&mut self, tree: &Tree<String>) -> bool {
+        let row = self.row(tree);
+        let basis_size = self.basis.len();
+        let mut new_h_b = self.h_b.clone().resize_vertically(basis_size + 1, 0.0);
+        new_h_b.set_row(basis_size, &row.row(0));
+        if new_h_b.rank(EPS) > basis_size {
+            debug!("Adding {tree} to the basis");
+            self.h_b = new_h_b;
+            self.basis.push(tree.clone());
+            return true;
+        }
+        return false;
+    }
+
+    // Recomputes the rows of the basis after C has changed and adds all trees of T that became independent.
+    fn refill(&mut self) {
+        let basis = self.basis.clone();
+        self.h_b = DynamicMatrix::from_element(0, self.contexts.len(), 0.0);
+        self.basis = Vec::new();
+        for tree in basis.iter().chain(self.trees.clone().iter()) {
+            self.try_add_to_basis(tree);
+        }
+    }
+
+    // All trees σ(b_1, ..., b_k) with b_i ∈ B in the order of the rows of μ_σ.
+    fn successors(&self, σ: &str, rk_σ: usize) -> Vec<Tree<String>> {
+        let mut successors = Vec::new();
+        let basis_size = self.basis.len();
+        if basis_size == 0 && rk_σ != 0 {
+            return successors;
+        }
+        let mut x: Vec<usize> = vec![1; rk_σ];
+        loop {
+            let mut tree = Tree::new(σ.to_owned());
+            for index in &x {
+                tree.push_back(self.basis[index - 1].clone());
+            }
+            successors.push(tree);
+            if let Some(temp_x) = next_tuple(x, basis_size, false) {
+                x = temp_x;
+            } else {
+                break;
+            }
+        }
+        return successors;
+    }
+
+    // Adds successors to the basis until the table is closed.
+    fn close(&mut self) {
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (σ, rk_σ) in self.ranked_alphabet.clone().iter() {
+                for tree in self.successors(σ, *rk_σ) {
+                    if self.try_add_to_basis(&tree) {
+                        self.trees.push(tree);
+                        changed = true;
+                    }
+                }
+            }
+        }
+    }
+
+    // The coefficients α with α H_B = H[t, C].
+    fn coefficients(&mut self, tree: &Tree<String>) -> DynamicMatrix {
+        let row = self.row(tree);
+        if self.basis.is_empty() {
+            return DynamicMatrix::from_element(1, 0, 0.0);
+        }
+        let h_b_decomp = self.h_b.transpose().svd(true, true);
+        return h_b_decomp.solve(&row.transpose(), EPS).unwrap().transpose();
+    }
+
+    fn hypothesis(&mut self) -> WeightedTreeAutomatonMatrix {
+        let n = self.basis.len();
+        let mut transition_matrices = BTreeMap::new();
+        for (σ, rk_σ) in self.ranked_alphabet.clone().iter() {
+            let mut μ_σ = DynamicMatrix::from_element(n.pow(*rk_σ as u32), n, 0.0);
+            for (row_num, tree) in self.successors(σ, *rk_σ).iter().enumerate() {
+                let α = self.coefficients(tree);
+                μ_σ.set_row(row_num, &α.row(0));
+            }
+            transition_matrices.insert(σ.clone(), μ_σ);
+        }
+        let mut γ = DynamicMatrix::from_element(n, 1, 0.0);
+        for (index, tree) in self.basis.clone().iter().enumerate() {
+            γ[(index, 0)] = self.weight(tree);
+        }
+        transition_matrices.insert("!".to_owned(), γ);
+
+        let mut ranked_alphabet = self.ranked_alphabet.clone();
+        ranked_alphabet.insert("!".to_owned(), n);
+        return WeightedTreeAutomatonMatrix {
+            num_states: n,
+            grammar_type: GrammarType::BottomUp,
+            ranked_alphabet,
+            transition_matrices,
+        };
+    }
+
+    // Processes a counterexample as described at the top of this file.
+    fn process_counterexample(&mut self, hypothesis: &WeightedTreeAutomatonMatrix, z: &Tree<String>) {
+        // Find the lowest subtree whose hypothesis vector doesn't match its row.
+        fn find_wrong_subtree(
+            table: &mut ObservationTable,
+            hypothesis: &WeightedTreeAutomatonMatrix,
+            node: &Node<String>,
+        ) -> Option<Tree<String>> {
+            for child in node.iter() {
+                if let Some(wrong_subtree) = find_wrong_subtree(table, hypothesis, child) {
+                    return Some(wrong_subtree);
+                }
+            }
+            let s = node.deep_clone();
+            let α = compute_vector_for_tree(hypothesis, &s);
+            let row = table.row(&s);
+            let expected = &α * &table.h_b;
+            if (&expected - &row).amax() > EPS * (1.0 + row.amax()) {
+                return Some(s);
+            }
+            return None;
+        }
+        let s = find_wrong_subtree(self, hypothesis, z.root()).unwrap();
+        debug!("Wrong subtree: {s}");
+        let σ = s.root().data().clone();
+        let children: Vec<Tree<String>> = s.root().iter().map(|child| child.deep_clone()).collect();
+        let k = children.len();
+        let n = self.basis.len();
+        let α_children: Vec<DynamicMatrix> = children.iter().map(|child| compute_vector_for_tree(hypothesis, child)).collect();
+
+        // X_j = Σ_{i_1, ..., i_j} α(s_1)_{i_1} ... α(s_j)_{i_j} H[σ(b_{i_1}, ..., b_{i_j}, s_{j+1}, ..., s_k), C]
+        let mut previous_x = self.row(&s);
+        for j in 1..(k + 1) {
+            let mut x_j = DynamicMatrix::from_element(1, self.contexts.len(), 0.0);
+            let mut prefix_contexts = Vec::new();
+            let mut tuple: Vec<usize> = vec![1; j];
+            if n > 0 {
+                loop {
+                    let mut factor = 1.0;
+                    let mut tree = Tree::new(σ.clone());
+                    let mut context = Tree::new(σ.clone());
+                    for (position, index) in tuple.iter().enumerate() {
+                        factor *= α_children[position][(0, index - 1)];
+                        tree.push_back(self.basis[index - 1].clone());
+                        if position < j - 1 {
+                            context.push_back(self.basis[index - 1].clone());
+                        }
+                    }
+                    context.push_back(Tree::new(CONTEXT_HOLE.to_owned()));
+                    for child in children.iter().skip(j) {
+                        tree.push_back(child.clone());
+                        context.push_back(child.clone());
+                    }
+                    x_j += factor * self.row(&tree);
+                    prefix_contexts.push(context);
+                    if let Some(temp_tuple) = next_tuple(tuple, n, false) {
+                        tuple = temp_tuple;
+                    } else {
+                        break;
+                    }
+                }
+            } else {
+                // Without basis trees the sum is empty, but we still need the context σ(□, s_2, ..., s_k).
+                let mut context = Tree::new(σ.clone());
+                context.push_back(Tree::new(CONTEXT_HOLE.to_owned()));
+                for child in children.iter().skip(1) {
+                    context.push_back(child.clone());
+                }
+                prefix_contexts.push(context);
+            }
+
+            if (&x_j - &previous_x).amax() > EPS * (1.0 + previous_x.amax()) {
+                // The contexts c[σ(b_{i_1}, ..., b_{i_{j-1}}, □, s_{j+1}, ..., s_k)] separate s_j from its expansion.
+                let old_contexts = self.contexts.clone();
+                for prefix_context in prefix_contexts {
+                    for context in old_contexts.iter() {
+                        let new_context = plug_into_context(context, &prefix_context);
+                        if !self.contexts.contains(&new_context) {
+                            self.contexts.push(new_context);
+                        }
+                    }
+                }
+                let s_j = children[j - 1].clone();
+                debug!("Separating {s_j}, now using {} contexts", self.contexts.len());
+                self.trees.push(s_j);
+                self.refill();
+                return;
+            }
+            previous_x = x_j;
+        }
+        panic!("The counterexample {z} doesn't contain a subtree that can be separated.");
+    }
+}
+
+// Learns a minimal automaton for the tree series of the teacher over the given ranked alphabet.
+pub fn learn_automaton(
+    teacher: &dyn Teacher,
+    ranked_alphabet: &BTreeMap<String, usize>,
+) -> WeightedTreeAutomatonMatrix {
+    let mut ranked_alphabet = ranked_alphabet.clone();
+    ranked_alphabet.remove("!");
+    let mut table = ObservationTable {
+        teacher,
+        ranked_alphabet,
+        trees: Vec::new(),
+        contexts: vec![Tree::new(CONTEXT_HOLE.to_owned())],
+        basis: Vec::new(),
+        h_b: DynamicMatrix::from_element(0, 1, 0.0),
+        cache: BTreeMap::new(),
+    };
+    loop {
+        table.close();
+        let hypothesis = table.hypothesis();
+        info!("Hypothesis with {} states", hypothesis.num_states);
+        match teacher.equivalence_query(&hypothesis) {
+            None => return hypothesis,
+            Some(z) => {
+                info!("Counterexample: {z}");
+                table.process_counterexample(&hypothesis, &z);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{data_structures, equivalence, parser};
+
+    #[test]
+    fn learn_counter_example() {
+        let automaton = data_structures::prod_vec_to_automaton(
+            parser::read_bottom_up_grammar("automata/bottom_up/counter_tree"),
+        );
+        let teacher = AutomatonTeacher { automaton: automaton.clone() };
+        let learned = learn_automaton(&teacher, &automaton.ranked_alphabet);
+        assert_eq!(learned.num_states, 2);
+        assert!(equivalence::are_equivalent(&automaton, &learned));
+    }
+
+    #[test]
+    fn learn_computation_example() {
+        let automaton = data_structures::prod_vec_to_automaton(
+            parser::read_bottom_up_grammar("automata/bottom_up/computation_tree"),
+        );
+        let teacher = AutomatonTeacher { automaton: automaton.clone() };
+        let learned = learn_automaton(&teacher, &automaton.ranked_alphabet);
+        assert_eq!(learned.num_states, 2);
+        assert!(equivalence::are_equivalent(&automaton, &learned));
+    }
+}
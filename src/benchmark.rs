@@ -0,0 +1,163 @@
+use crate::data_structures::WeightedTreeAutomatonMatrix;
+use crate::random_automaton::{random_automaton_matrix, RandomAutomatonConfig};
+use crate::step_i::step_i_forward_with_trees;
+use crate::step_ii::step_ii_backward;
+use crate::step_iii::step_iii_solve;
+use log::info;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/*
+    Benchmarks for the three steps of the minimisation. Every family of random automata (see random_automaton.rs)
+    increases one parameter while the others stay fixed:
+    - "states": 2, 3, ... states with the symbols a, b (rank 0), g (rank 1) and s (rank 2),
+    - "rank": 3 states and the symbols a, b together with one symbol of each rank 1, ..., k,
+    - "alphabet": 3 states and the symbols a, b together with 1, 2, ... symbols of alternating rank 1 and 2.
+    In the "states" family half of the states (rounded down) are redundant, in the other two families the minimal
+    automaton has 2 of the 3 states. Each step is run repetitions times and the fastest run is reported, since it is
+    the least disturbed by other processes. The results are written as CSV, one line per automaton.
+*/
+
+pub const FAMILIES: [&str; 3] = ["states", "rank", "alphabet"];
+
+pub const CSV_HEADER: &str =
+    "family,parameter,num_states,max_rank,alphabet_size,minimised_states,step_i_seconds,step_ii_seconds,step_iii_seconds";
+
+pub struct BenchmarkResult {
+    pub family: String,
+    pub parameter: usize,
+    pub num_states: usize,
+    pub max_rank: usize,
+    pub alphabet_size: usize,
+    pub minimised_states: usize,
+    pub step_i: Duration,
+    pub step_ii: Duration,
+    pub step_iii: Duration,
+}
+
+impl BenchmarkResult {
+    pub fn to_csv(&self) -> String {
+        return format!(
+            "{},{},{},{},{},{},{:.6},{:.6},{:.6}",
+            self.family,
+            self.parameter,
+            self.num_states,
+            self.max_rank,
+            self.alphabet_size,
+            self.minimised_states,
+            self.step_i.as_secs_f64(),
+            self.step_ii.as_secs_f64(),
+            self.step_iii.as_secs_f64()
+        );
+    }
+}
+
+// Default largest parameter of each family, chosen so that the whole benchmark only takes a few seconds.
+pub fn default_max_parameter(family: &str) -> usize {
+    match family {
+        "states" => 16,
+        "rank" => 6,
+        "alphabet" => 16,
+        _ => panic!("Unknown benchmark family {family}, expected one of {FAMILIES:?}."),
+    }
+}
+
+// The configuration of the automaton with the given parameter in a family.
+pub fn family_config(family: &str, parameter: usize, seed: u64) -> RandomAutomatonConfig {
+    let mut config = RandomAutomatonConfig::new(3, 2, seed);
+    match family {
+        "states" => {
+            config.num_states = parameter;
+            config.minimal_states = parameter.div_ceil(2);
+        }
+        "rank" => {
+            config.ranked_alphabet = leaves();
+            for rank in 1..(parameter + 1) {
+                config.ranked_alphabet.insert(format!("f{rank}"), rank);
+            }
+        }
+        "alphabet" => {
+            config.ranked_alphabet = leaves();
+            for index in 0..parameter {
+                config.ranked_alphabet.insert(format!("f{index}"), 1 + index % 2);
+            }
+        }
+        _ => panic!("Unknown benchmark family {family}, expected one of {FAMILIES:?}."),
+    }
+    return config;
+}
+
+fn leaves() -> BTreeMap<String, usize> {
+    let mut ranked_alphabet = BTreeMap::new();
+    ranked_alphabet.insert("a".to_owned(), 0);
+    ranked_alphabet.insert("b".to_owned(), 0);
+    return ranked_alphabet;
+}
+
+// Times the three steps of the minimisation of one automaton, keeping the fastest of all repetitions.
+pub fn benchmark_automaton(
+    automaton_matrix: &WeightedTreeAutomatonMatrix,
+    repetitions: usize,
+) -> (Duration, Duration, Duration, usize) {
+    let mut step_i = Duration::MAX;
+    let mut step_ii = Duration::MAX;
+    let mut step_iii = Duration::MAX;
+    let mut minimised_states = 0;
+    for _ in 0..repetitions.max(1) {
+        let start = Instant::now();
+        let (f, spanning_trees) = step_i_forward_with_trees(automaton_matrix);
+        step_i = step_i.min(start.elapsed());
+
+        let start = Instant::now();
+        let b = step_ii_backward(automaton_matrix, &f, &spanning_trees);
+        step_ii = step_ii.min(start.elapsed());
+
+        let start = Instant::now();
+        let minimised = step_iii_solve(automaton_matrix, &f, &b);
+        step_iii = step_iii.min(start.elapsed());
+        minimised_states = minimised.num_states;
+    }
+    return (step_i, step_ii, step_iii, minimised_states);
+}
+
+// Runs the benchmark for all parameters 1, ..., max_parameter of a family (starting at 2 for "states").
+pub fn benchmark_family(family: &str, max_parameter: usize, repetitions: usize, seed: u64) -> Vec<BenchmarkResult> {
+    let first_parameter = if family == "states" { 2 } else { 1 };
+    let mut results = Vec::new();
+    for parameter in first_parameter..(max_parameter + 1) {
+        let config = family_config(family, parameter, seed);
+        let automaton_matrix = random_automaton_matrix(&config);
+        let (step_i, step_ii, step_iii, minimised_states) = benchmark_automaton(&automaton_matrix, repetitions);
+        let result = BenchmarkResult {
+            family: family.to_owned(),
+            parameter,
+            num_states: config.num_states,
+            max_rank: *config.ranked_alphabet.values().max().unwrap(),
+            alphabet_size: config.ranked_alphabet.len(),
+            minimised_states,
+            step_i,
+            step_ii,
+            step_iii,
+        };
+        info!("{}", result.to_csv());
+        results.push(result);
+    }
+    return results;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn benchmark_small_families() {
+        for family in FAMILIES {
+            let results = benchmark_family(family, 2, 1, 0);
+            assert!(!results.is_empty());
+            for result in results {
+                assert_eq!(result.to_csv().split(',').count(), CSV_HEADER.split(',').count());
+                assert!(result.minimised_states <= result.num_states);
+            }
+        }
+    }
+}
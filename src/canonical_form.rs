@@ -0,0 +1,169 @@
+use crate::computation::compute_vector_for_tree;
+use crate::data_structures::WeightedTreeAutomatonMatrix;
+use crate::step_i::step_i_forward_with_trees;
+use crate::step_ii::step_ii_backward;
+use nalgebra::DMatrix;
+use log::debug;
+use trees::Tree;
+
+type DynamicMatrix = DMatrix<f64>;
+
+// All singular values below eps are considered equal to 0 when used in numeric algorithms.
+const EPS: f64 = 0.00001;
+
+/*
+    Two minimal automata which recognise the same tree series only differ by an invertible change of basis.
+    This file picks one specific basis for a minimal automaton, such that equivalent minimal automata end up
+    with the same transition matrices.
+
+    The basis is given by the spanning trees t_1, ..., t_n found by step_i_forward: We run step I on the minimal
+    automaton and keep the first n trees whose forward vectors μ(t_i) are linearly independent. Since the
+    automaton is minimal, the linear dependencies between the vectors μ(t) only depend on the tree series and
+    not on the automaton, so the chosen trees are the same for all equivalent minimal automata.
+    Let P be the matrix with rows μ(t_1), ..., μ(t_n). The canonical automaton has:
+    μ'_σ = P^{⊗k} μ_σ P^{-1} and γ' = P γ,
+    which implies μ'(t) = μ(t) P^{-1} and in particular μ'(t_i) = e_i.
+
+    Note that everything is computed with f64, so the output of two equivalent automata is only equal up to
+    rounding errors.
+*/
+
+// Returns the spanning trees that define the canonical basis of the given minimal automaton together with the
+// matrix P whose rows are their forward vectors, or None if the automaton isn't minimal.
+pub fn canonical_basis(
+    automaton_matrix: &WeightedTreeAutomatonMatrix,
+) -> Option<(Vec<Tree<String>>, DynamicMatrix)> {
+    let n = automaton_matrix.num_states;
+    let (f, spanning_trees) = step_i_forward_with_trees(automaton_matrix);
+    // An automaton is minimal if and only if its forward space and its backward space both have dimension n.
+    let b = step_ii_backward(automaton_matrix, &f, &spanning_trees);
+    if b.ncols() != n {
+        debug!("The backward space has dimension {} but the automaton has {n} states", b.ncols());
+        return None;
+    }
+
+    let mut basis_trees = Vec::new();
+    let mut p = DynamicMatrix::from_element(0, n, 0.0);
+    let mut old_rank = 0;
+    for (row_num, tree) in spanning_trees.into_iter().enumerate() {
+        let mut temp_p = p.clone().resize_vertically(old_rank + 1, 0.0);
+        for index in 0..n {
+            temp_p[(old_rank, index)] = f[(row_num, index)];
+        }
+        // Only rows which are linearly independent from the previous ones are part of the basis.
+        if temp_p.rank(EPS) > old_rank {
+            debug!("Adding {tree} to the canonical basis");
+            old_rank += 1;
+            p = temp_p;
+            basis_trees.push(tree);
+        }
+    }
+    if old_rank != n {
+        debug!("The forward space has dimension {old_rank} but the automaton has {n} states");
+        return None;
+    }
+    return Some((basis_trees, p));
+}
+
+// Brings a minimal automaton into its canonical form. See the comment at the top of this file.
+pub fn canonicalise(automaton_matrix: &WeightedTreeAutomatonMatrix) -> WeightedTreeAutomatonMatrix {
+    let (_, p) = match canonical_basis(automaton_matrix) {
+        Some(basis) => basis,
+        None => panic!("Only minimal automata have a canonical form, minimise the automaton first."),
+    };
+    debug!("P: {p}");
+    // change_basis maps μ(t) to μ(t) Q, so Q = P^{-1} gives μ'(t_i) = e_i.
+    // The rows of P are linearly independent, so P is invertible.
+    let p_inverse = p.try_inverse().expect("The canonical basis matrix P is singular.");
+    return automaton_matrix.change_basis(&p_inverse);
+}
+
+// Given two minimal automata with the same number of states, this computes the invertible matrix P with
+// a.change_basis(P) = b, or returns None if the automata aren't related by a change of basis (which for
+// minimal automata is the case if and only if they recognise different tree series). It also returns None
+// if a isn't minimal.
+// Since both automata map the canonical spanning trees t_i of a to μ_b(t_i) = μ_a(t_i) P, P is determined
+// by the rows of those trees and we only have to check that it actually transforms all matrices of a into b.
+pub fn find_similarity(
+    a: &WeightedTreeAutomatonMatrix,
+    b: &WeightedTreeAutomatonMatrix,
+) -> Option<DynamicMatrix> {
+    if a.num_states != b.num_states || a.ranked_alphabet != b.ranked_alphabet {
+        debug!("The automata have different dimensions or alphabets");
+        return None;
+    }
+    let n = a.num_states;
+    let (basis_trees, p_a) = canonical_basis(a)?;
+    let mut p_b = DynamicMatrix::from_element(n, n, 0.0);
+    for (row_num, tree) in basis_trees.iter().enumerate() {
+        let μ_b_t = compute_vector_for_tree(b, tree);
+        for index in 0..n {
+            p_b[(row_num, index)] = μ_b_t[(0, index)];
+        }
+    }
+    let p = p_a.try_inverse()? * p_b;
+    debug!("Candidate P: {p}");
+    if p.rank(EPS) < n {
+        return None;
+    }
+
+    let transformed_a = a.change_basis(&p);
+    for (symbol, μ_σ) in transformed_a.transition_matrices.iter() {
+        let other = b.transition_matrices.get(symbol)?;
+        if (μ_σ - other).amax() > EPS * (1.0 + other.amax()) {
+            debug!("{symbol} doesn't match: {μ_σ} != {other}");
+            return None;
+        }
+    }
+    return Some(p);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{computation, data_structures, parser};
+
+    #[test]
+    fn canonical_counter_example() {
+        // counter_tree_times_two is already a minimal automaton for the same series as counter_tree, but in a different basis.
+        let automaton_one = data_structures::prod_vec_to_automaton(
+            parser::read_bottom_up_grammar("automata/bottom_up/counter_tree_times_two"),
+        );
+        let p = DynamicMatrix::from_row_slice(2, 2, &[2.0, 1.0, 1.0, 1.0]);
+        let automaton_two = automaton_one.change_basis(&p);
+
+        let canonical_one = canonicalise(&automaton_one);
+        let canonical_two = canonicalise(&automaton_two);
+        for (symbol, μ_σ) in canonical_one.transition_matrices.iter() {
+            let other = canonical_two.transition_matrices.get(symbol).unwrap();
+            assert!((μ_σ - other).amax() < EPS, "{symbol}: {μ_σ} != {other}");
+        }
+
+        let tree = trees::Tree::<char>::from_tuple(('s', ('s', 'b', 'b'), 'b'));
+        let val = computation::compute_val_for_tree(canonical_one, tree);
+        assert!((val + 3.0).abs() < EPS);
+    }
+
+    #[test]
+    fn similarity_counter_example() {
+        let automaton_one = data_structures::prod_vec_to_automaton(
+            parser::read_bottom_up_grammar("automata/bottom_up/counter_tree_times_two"),
+        );
+        let p = DynamicMatrix::from_row_slice(2, 2, &[2.0, 1.0, 1.0, 1.0]);
+        let automaton_two = automaton_one.change_basis(&p);
+        let found_p = find_similarity(&automaton_one, &automaton_two).unwrap();
+        assert!((found_p - p).amax() < EPS);
+
+        // Doubling the final weights changes the tree series, so there is no change of basis between the two.
+        let mut automaton_three = automaton_one.clone();
+        let γ = automaton_three.transition_matrices.get_mut("!").unwrap();
+        *γ *= 2.0;
+        assert!(find_similarity(&automaton_one, &automaton_three).is_none());
+
+        // counter_tree has three states but recognises the same series with two, so it isn't minimal.
+        let non_minimal = data_structures::prod_vec_to_automaton(
+            parser::read_bottom_up_grammar("automata/bottom_up/counter_tree"),
+        );
+        assert!(find_similarity(&non_minimal, &non_minimal).is_none());
+    }
+}
@@ -0,0 +1,453 @@
+use crate::data_structures::{ordered_states, top_down_to_bottom_up, GrammarType, WeightedTreeAutomatonStruct};
+use nalgebra::DMatrix;
+use log::debug;
+use std::collections::HashMap;
+use trees::Tree;
+
+type DynamicMatrix = DMatrix<f64>;
+
+/*
+    CYK parsing of sentences with a binarised grammar. The sentence w_1 ... w_m is the yield of a tree, that is the
+    sequence of its leaves, so the words are the symbols of rank 0 (the lexicon of read_top_down_automaton). The
+    chart contains for every span w_i ... w_{j-1} and every state q:
+    - the inside weight, the sum of the weights of all runs on trees with the yield of the span that end in q,
+    - the log weight of the best such run, together with a backpointer to reconstruct it.
+    Symbols of rank 2 combine two neighbouring spans. Symbols of rank 1 are applied within a span until nothing
+    changes: the inside weights solve x = x_0 + x W with W[p, q] = Σ_σ μ_σ[p, q], so x = x_0 (I - W)^{-1} also
+    contains arbitrarily long unary chains, and the best runs are improved like in the Bellman-Ford algorithm.
+    Symbols of a higher rank have to be binarised first.
+*/
+
+#[derive(Debug, Clone)]
+pub struct ChartParse {
+    // The sum of ||A||(t) over all trees t with the sentence as yield.
+    pub total_weight: f64,
+    // The tree with the best run together with the log weight of this run. Only computed if all weights are
+    // non-negative, None if there is no run with a positive weight.
+    pub best_parse: Option<(Tree<String>, f64)>,
+}
+
+// How the best run of a chart entry was built.
+#[derive(Debug, Clone)]
+enum Backpointer {
+    Leaf { symbol: String },
+    Unary { symbol: String, child: usize },
+    Binary { symbol: String, split: usize, left: usize, right: usize },
+}
+
+struct ChartCell {
+    inside: DynamicMatrix,
+    best: Vec<f64>,
+    backpointers: Vec<Option<Backpointer>>,
+}
+
+// The productions of a bottom-up automaton sorted by their rank, with the states replaced by their indices. Building
+// the parser inverts I - W, so it should be reused for all sentences of the same automaton.
+pub struct ChartParser {
+    num_states: usize,
+    lexicon: HashMap<String, Vec<(usize, f64)>>,
+    unary: Vec<(String, usize, usize, f64)>,
+    binary: Vec<(String, usize, usize, usize, f64)>,
+    final_weights: DynamicMatrix,
+    // (I - W)^{-1} for the sum W of all unary transition matrices.
+    unary_closure: DynamicMatrix,
+    non_negative: bool,
+}
+
+impl ChartParser {
+    pub fn new(automaton_struct: &WeightedTreeAutomatonStruct) -> ChartParser {
+        if let GrammarType::TopDown = automaton_struct.grammar_type {
+            panic!("The chart parser expects a bottom-up automaton, use parse_sentence_top_down for top-down grammars.");
+        }
+        let states = ordered_states(automaton_struct);
+        let index: HashMap<&String, usize> = states.iter().enumerate().map(|(i, state)| (state, i)).collect();
+        let n = states.len();
+        let mut parser = ChartParser {
+            num_states: n,
+            lexicon: HashMap::new(),
+            unary: Vec::new(),
+            binary: Vec::new(),
+            final_weights: DynamicMatrix::from_element(n, 1, 0.0),
+            unary_closure: DynamicMatrix::identity(n, n),
+            non_negative: true,
+        };
+        let mut w = DynamicMatrix::from_element(n, n, 0.0);
+        for (σ, productions) in automaton_struct.transition_structs.iter() {
+            for production in productions {
+                parser.non_negative &= production.cost >= 0.0;
+                if σ == "!" {
+                    parser.final_weights[(index[&production.left_states[0]], 0)] = production.cost;
+                    continue;
+                }
+                let target = index[&production.right_states[0]];
+                let children: Vec<usize> = production.left_states.iter().map(|state| index[state]).collect();
+                match children.len() {
+                    0 => parser.lexicon.entry(σ.clone()).or_default().push((target, production.cost)),
+                    1 => {
+                        w[(children[0], target)] += production.cost;
+                        parser.unary.push((σ.clone(), children[0], target, production.cost));
+                    }
+                    2 => parser.binary.push((σ.clone(), children[0], children[1], target, production.cost)),
+                    rank => panic!("The symbol {σ} has rank {rank}, the grammar has to be binarised for parsing."),
+                }
+            }
+        }
+        // Σ_k W^k only converges to (I - W)^{-1} if the spectral radius of W is below 1. Otherwise the inverse (if it
+        // exists) is meaningless, and for non-negative weights there is a unary cycle of weight >= 1.
+        let spectral_radius = w.complex_eigenvalues().iter().map(|λ| λ.re.hypot(λ.im)).fold(0.0, f64::max);
+        debug!("Spectral radius of the unary transitions: {spectral_radius}");
+        if spectral_radius >= 1.0 {
+            panic!(
+                "The unary chains of the grammar have an infinite total weight (the sum of the unary transition matrices has spectral radius {spectral_radius} >= 1)."
+            );
+        }
+        parser.unary_closure = (DynamicMatrix::identity(n, n) - w)
+            .try_inverse()
+            .expect("I - W is singular, although the spectral radius of W is below 1.");
+        return parser;
+    }
+
+    // Adds the unary chains to the entries of a cell that were built from smaller spans.
+    fn close_unary(&self, cell: &mut ChartCell) {
+        cell.inside = &cell.inside * &self.unary_closure;
+        if !self.non_negative {
+            return;
+        }
+        // The spectral radius check in new rules out cycles of weight >= 1, so the best unary chain has less than
+        // n steps.
+        for _ in 0..self.num_states {
+            let mut changed = false;
+            for (symbol, child, target, cost) in self.unary.iter() {
+                let weight = cell.best[*child] + cost.ln();
+                // A backpointer into the own unary chain would make build_tree loop forever, so rounding errors
+                // must never close a cycle.
+                if weight > cell.best[*target] && !unary_chain_contains(cell, *child, *target, self.num_states) {
+                    cell.best[*target] = weight;
+                    cell.backpointers[*target] = Some(Backpointer::Unary { symbol: symbol.clone(), child: *child });
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+    }
+
+    // Parses a sentence, the words have to be the names of the symbols of rank 0.
+    pub fn parse(&self, sentence: &[String]) -> ChartParse {
+        let m = sentence.len();
+        let n = self.num_states;
+        // chart[i][j] contains the span w_i ... w_{j-1}, only the entries with i < j are used.
+        let mut chart: Vec<Vec<ChartCell>> =
+            (0..m).map(|_| (0..(m + 1)).map(|_| self.empty_cell()).collect()).collect();
+
+        for (i, word) in sentence.iter().enumerate() {
+            let cell = &mut chart[i][i + 1];
+            for (target, cost) in self.lexicon.get(word).into_iter().flatten() {
+                cell.inside[(0, *target)] += cost;
+                if self.non_negative && cost.ln() > cell.best[*target] {
+                    cell.best[*target] = cost.ln();
+                    cell.backpointers[*target] = Some(Backpointer::Leaf { symbol: word.clone() });
+                }
+            }
+            self.close_unary(cell);
+        }
+
+        for length in 2..(m + 1) {
+            for i in 0..(m + 1 - length) {
+                let j = i + length;
+                let mut cell = self.empty_cell();
+                for (split, left_cell) in chart[i].iter().enumerate().take(j).skip(i + 1) {
+                    let right_cell = &chart[split][j];
+                    for (symbol, left, right, target, cost) in self.binary.iter() {
+                        cell.inside[(0, *target)] +=
+                            left_cell.inside[(0, *left)] * right_cell.inside[(0, *right)] * cost;
+                        if !self.non_negative {
+                            continue;
+                        }
+                        let weight = left_cell.best[*left] + right_cell.best[*right] + cost.ln();
+                        if weight > cell.best[*target] {
+                            cell.best[*target] = weight;
+                            cell.backpointers[*target] = Some(Backpointer::Binary {
+                                symbol: symbol.clone(),
+                                split,
+                                left: *left,
+                                right: *right,
+                            });
+                        }
+                    }
+                }
+                self.close_unary(&mut cell);
+                chart[i][j] = cell;
+            }
+        }
+
+        if m == 0 {
+            // Every tree has at least one leaf.
+            return ChartParse { total_weight: 0.0, best_parse: None };
+        }
+        let root_cell = &chart[0][m];
+        let total_weight = (&root_cell.inside * &self.final_weights)[(0, 0)];
+        debug!("Total weight of the sentence: {total_weight}");
+
+        let mut best_parse = None;
+        if self.non_negative {
+            let mut best_weight = f64::NEG_INFINITY;
+            let mut best_state = 0;
+            for q in 0..n {
+                let weight = root_cell.best[q] + self.final_weights[(q, 0)].ln();
+                if weight > best_weight {
+                    best_weight = weight;
+                    best_state = q;
+                }
+            }
+            if best_weight > f64::NEG_INFINITY {
+                best_parse = Some((build_tree(&chart, 0, m, best_state), best_weight));
+            }
+        }
+        return ChartParse { total_weight, best_parse };
+    }
+
+    fn empty_cell(&self) -> ChartCell {
+        return ChartCell {
+            inside: DynamicMatrix::from_element(1, self.num_states, 0.0),
+            best: vec![f64::NEG_INFINITY; self.num_states],
+            backpointers: vec![None; self.num_states],
+        };
+    }
+}
+
+// Parses a sentence with a bottom-up automaton, the words have to be the names of its symbols of rank 0.
+pub fn parse_sentence(automaton_struct: &WeightedTreeAutomatonStruct, sentence: &[String]) -> ChartParse {
+    return ChartParser::new(automaton_struct).parse(sentence);
+}
+
+// Parses a sentence with a top-down grammar (for example read by read_top_down_automaton) whose runs start in the
+// root state. The parse trees use the symbols of top_down_to_bottom_up, so symbols with several ranks are named "σ/k".
+pub fn parse_sentence_top_down(
+    grammar: &WeightedTreeAutomatonStruct,
+    root_state: &str,
+    sentence: &[String],
+) -> ChartParse {
+    return parse_sentence(&top_down_to_bottom_up(grammar, root_state), sentence);
+}
+
+// Parses every sentence with both bottom-up automata and returns the pairs of sentence weights together with the
+// largest relative difference |a - b| / max(|a|, |b|) of a pair. The first automaton is the reference: sentences whose
+// weight under it is at most zero_threshold in absolute value are skipped, since the minimised automaton usually has
+// rounding errors like 1e-33 instead of 0, which would give a relative difference of 1. An absolute threshold for both
+// weights doesn't work, because long sentences easily have weights below 1e-30.
+pub fn compare_yields(
+    first: &WeightedTreeAutomatonStruct,
+    second: &WeightedTreeAutomatonStruct,
+    sentences: &[Vec<String>],
+    zero_threshold: f64,
+) -> (Vec<(f64, f64)>, f64) {
+    let first_parser = ChartParser::new(first);
+    let second_parser = ChartParser::new(second);
+    let mut weights = Vec::new();
+    let mut max_difference: f64 = 0.0;
+    for sentence in sentences {
+        let first_weight = first_parser.parse(sentence).total_weight;
+        let second_weight = second_parser.parse(sentence).total_weight;
+        if first_weight.abs() > zero_threshold {
+            let scale = first_weight.abs().max(second_weight.abs());
+            max_difference = max_difference.max((first_weight - second_weight).abs() / scale);
+        }
+        weights.push((first_weight, second_weight));
+    }
+    return (weights, max_difference);
+}
+
+// Checks whether following the unary backpointers of the cell from state q reaches the given state.
+fn unary_chain_contains(cell: &ChartCell, mut q: usize, state: usize, num_states: usize) -> bool {
+    for _ in 0..(num_states + 1) {
+        if q == state {
+            return true;
+        }
+        match cell.backpointers[q] {
+            Some(Backpointer::Unary { child, .. }) => q = child,
+            _ => return false,
+        }
+    }
+    return true;
+}
+
+// Follows the backpointers of the best run of the span w_i ... w_{j-1} that ends in state q.
+fn build_tree(chart: &[Vec<ChartCell>], i: usize, j: usize, q: usize) -> Tree<String> {
+    match chart[i][j].backpointers[q].as_ref().unwrap() {
+        Backpointer::Leaf { symbol } => return Tree::new(symbol.clone()),
+        Backpointer::Unary { symbol, child } => {
+            let mut tree = Tree::new(symbol.clone());
+            tree.push_back(build_tree(chart, i, j, *child));
+            return tree;
+        }
+        Backpointer::Binary { symbol, split, left, right } => {
+            let mut tree = Tree::new(symbol.clone());
+            tree.push_back(build_tree(chart, i, *split, *left));
+            tree.push_back(build_tree(chart, *split, j, *right));
+            return tree;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::computation::compute_val_for_tree;
+    use crate::data_structures::{automaton_to_prod_vec, prod_vec_to_automaton, Production};
+    use crate::minimisation::minimize_automaton;
+    use crate::parser;
+    use std::collections::BTreeMap;
+
+    fn words(sentence: &str) -> Vec<String> {
+        return sentence.split_whitespace().map(|word| word.to_owned()).collect();
+    }
+
+    fn leaf(label: &str) -> Tree<String> {
+        return Tree::new(label.to_owned());
+    }
+
+    fn node(label: &str, children: Vec<Tree<String>>) -> Tree<String> {
+        let mut tree = Tree::new(label.to_owned());
+        for child in children {
+            tree.push_back(child);
+        }
+        return tree;
+    }
+
+    #[test]
+    fn parse_pp_attachment() {
+        let grammar = parser::read_top_down_automaton(
+            "automata/top_down/simple_pcfg.grammar",
+            "automata/top_down/simple_pcfg.lexicon",
+        );
+        let automaton = prod_vec_to_automaton(top_down_to_bottom_up(&grammar, "ROOT_0"));
+        // The prepositional phrase belongs either to the verb phrase or to the noun phrase.
+        let np = |word: &str| node("NP/2", vec![leaf("the"), leaf(word)]);
+        let pp = node("PP", vec![leaf("with"), np("telescope")]);
+        let vp_attachment = node("ROOT", vec![node("S", vec![
+            np("dog"),
+            node("VP", vec![node("VP", vec![leaf("saw"), np("dog")]), pp.clone()]),
+        ])]);
+        let np_attachment = node("ROOT", vec![node("S", vec![
+            np("dog"),
+            node("VP", vec![leaf("saw"), node("NP/2", vec![np("dog"), pp])]),
+        ])]);
+        let vp_weight = compute_val_for_tree(automaton.clone(), vp_attachment.clone());
+        let np_weight = compute_val_for_tree(automaton.clone(), np_attachment);
+        assert!(vp_weight > np_weight);
+
+        let parse = parse_sentence_top_down(&grammar, "ROOT_0", &words("the dog saw the dog with the telescope"));
+        assert!((parse.total_weight - (vp_weight + np_weight)).abs() < 1e-12);
+        let (best_tree, best_weight) = parse.best_parse.unwrap();
+        assert_eq!(best_tree.to_string(), vp_attachment.to_string());
+        assert!((best_weight - vp_weight.ln()).abs() < 1e-12);
+
+        // The minimised automaton assigns the same total weight, but may have negative weights.
+        let minimised = automaton_to_prod_vec(&minimize_automaton(automaton.clone()));
+        let minimised_parse = parse_sentence(&minimised, &words("the dog saw the dog with the telescope"));
+        assert!((minimised_parse.total_weight - parse.total_weight).abs() < 1e-9);
+    }
+
+    #[test]
+    fn parse_unary_and_unknown_words() {
+        let grammar = parser::read_top_down_automaton(
+            "automata/top_down/simple_pcfg.grammar",
+            "automata/top_down/simple_pcfg.lexicon",
+        );
+        let automaton = prod_vec_to_automaton(top_down_to_bottom_up(&grammar, "ROOT_0"));
+        let tree = node("ROOT", vec![node("S", vec![
+            node("NP/1", vec![leaf("dog")]),
+            node("VP", vec![leaf("saw"), node("NP/1", vec![leaf("telescope")])]),
+        ])]);
+        let parse = parse_sentence_top_down(&grammar, "ROOT_0", &words("dog saw telescope"));
+        assert!((parse.total_weight - compute_val_for_tree(automaton, tree.clone())).abs() < 1e-12);
+        assert_eq!(parse.best_parse.unwrap().0.to_string(), tree.to_string());
+
+        let parse = parse_sentence_top_down(&grammar, "ROOT_0", &words("dog saw cat"));
+        assert_eq!(parse.total_weight, 0.0);
+        assert!(parse.best_parse.is_none());
+
+        // A parser can be reused for several sentences.
+        let chart_parser = ChartParser::new(&top_down_to_bottom_up(&grammar, "ROOT_0"));
+        assert_eq!(chart_parser.parse(&words("dog saw cat")).total_weight, 0.0);
+        let parse = chart_parser.parse(&words("dog saw telescope"));
+        assert_eq!(parse.best_parse.unwrap().0.to_string(), tree.to_string());
+    }
+
+    #[test]
+    fn compare_yields_of_minimised_grammar() {
+        let grammar = parser::read_top_down_automaton(
+            "automata/top_down/split_pcfg.grammar",
+            "automata/top_down/split_pcfg.lexicon",
+        );
+        let automaton = prod_vec_to_automaton(top_down_to_bottom_up(&grammar, "ROOT_0"));
+        let minimised = minimize_automaton(automaton.clone());
+        assert!(minimised.num_states <= automaton.num_states);
+        let sentences = parser::read_words("automata/top_down/simple_pcfg.sentences");
+        let (weights, max_difference) =
+            compare_yields(&automaton_to_prod_vec(&automaton), &automaton_to_prod_vec(&minimised), &sentences, 0.0);
+        assert_eq!(weights.len(), sentences.len());
+        // "saw the dog" is not a sentence of the grammar.
+        assert_eq!(weights.last().unwrap().0, 0.0);
+        assert!(weights[0].0 > 0.0);
+        assert!(max_difference < 1e-9);
+    }
+
+    #[test]
+    fn compare_yields_of_long_sentence() {
+        // a -> X with weight 0.001 and s(X, X) -> X with the given weight, so a^30 has a weight of about 1e-102.
+        let grammar = |binary_weight: f64| {
+            let production = |symbol: &str, left_states: Vec<&str>, right_states: Vec<&str>, cost: f64| Production {
+                root_symbol: symbol.to_owned(),
+                left_states: left_states.into_iter().map(|state| state.to_owned()).collect(),
+                right_states: right_states.into_iter().map(|state| state.to_owned()).collect(),
+                cost,
+            };
+            let mut transition_structs = BTreeMap::new();
+            transition_structs.insert("a".to_owned(), vec![production("a", vec![], vec!["X"], 0.001)]);
+            transition_structs
+                .insert("s".to_owned(), vec![production("s", vec!["X", "X"], vec!["X"], binary_weight)]);
+            transition_structs.insert("!".to_owned(), vec![production("!", vec!["X"], vec![], 1.0)]);
+            let ranked_alphabet = BTreeMap::from([("a".to_owned(), 0), ("s".to_owned(), 2), ("!".to_owned(), 1)]);
+            return WeightedTreeAutomatonStruct {
+                num_states: 1,
+                grammar_type: GrammarType::BottomUp,
+                ranked_alphabet,
+                transition_structs,
+            };
+        };
+        let sentences = vec![words(&["a"; 30].join(" "))];
+        let (weights, max_difference) = compare_yields(&grammar(0.001), &grammar(0.0011), &sentences, 0.0);
+        assert!(weights[0].0 > 0.0 && weights[0].0 < 1e-100);
+        // The different weight of s is used 29 times, so the weights differ by far more than 1e-30.
+        assert!(max_difference > 0.9);
+        let (_, max_difference) = compare_yields(&grammar(0.001), &grammar(0.001), &sentences, 0.0);
+        assert_eq!(max_difference, 0.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "infinite total weight")]
+    fn reject_unary_cycle_of_weight_two() {
+        // a -> X, X -> X with weight 2 and the final weight 1 for X, so the unary chains on a sum to 1 + 2 + 4 + ...
+        let production = |symbol: &str, left_states: Vec<&str>, right_states: Vec<&str>, cost: f64| Production {
+            root_symbol: symbol.to_owned(),
+            left_states: left_states.into_iter().map(|state| state.to_owned()).collect(),
+            right_states: right_states.into_iter().map(|state| state.to_owned()).collect(),
+            cost,
+        };
+        let mut transition_structs = BTreeMap::new();
+        transition_structs.insert("a".to_owned(), vec![production("a", vec![], vec!["X"], 1.0)]);
+        transition_structs.insert("u".to_owned(), vec![production("u", vec!["X"], vec!["X"], 2.0)]);
+        transition_structs.insert("!".to_owned(), vec![production("!", vec!["X"], vec![], 1.0)]);
+        let ranked_alphabet = BTreeMap::from([("a".to_owned(), 0), ("u".to_owned(), 1), ("!".to_owned(), 1)]);
+        let automaton_struct = WeightedTreeAutomatonStruct {
+            num_states: 1,
+            grammar_type: GrammarType::BottomUp,
+            ranked_alphabet,
+            transition_structs,
+        };
+        parse_sentence(&automaton_struct, &words("a"));
+    }
+}
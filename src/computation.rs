@@ -6,6 +6,7 @@ use nalgebra::DMatrix;
 use trees::Tree;
 use trees::{tr, Node};
 use log::{debug, info};
+use std::fmt;
 
 
 type DynamicMatrix = DMatrix<f64>;
@@ -16,12 +17,36 @@ pub fn minimize_automaton(automaton_struct: WeightedTreeAutomatonMatrix) -> Weig
 
 
 // This is a simple, naive implementation that computes ||A||(t).
-pub fn compute_val_for_tree(
+// The labels of the tree can be of any type whose string representation is a symbol of the ranked alphabet,
+// for example char or String.
+pub fn compute_val_for_tree<T: fmt::Display>(
     automaton_struct: WeightedTreeAutomatonMatrix,
-    tree: Tree<char>,
+    tree: Tree<T>,
 ) -> f64 {
     debug!("Tree to be evaluated: {tree}");
-    fn calculate_weight_rec(auto: &WeightedTreeAutomatonMatrix, node: &Node<char>) -> DynamicMatrix {
+    if let data_structures::GrammarType::TopDown = automaton_struct.grammar_type {
+        let ι = automaton_struct.transition_matrices.get("!").unwrap();
+        let result = ι * compute_top_down_vector_for_tree(&automaton_struct, &tree);
+        debug!("Result: {}", result);
+        return result[(0, 0)];
+    }
+    let mut result = compute_vector_for_tree(&automaton_struct, &tree);
+    result *= automaton_struct
+            .transition_matrices
+            .get("!")
+            .unwrap()
+            .clone();
+    debug!("Result: {}", result);
+    return result[(0, 0)];
+}
+
+// Computes the row vector μ(t) of the tree t, that is the weight of t in each state before the final weight
+// vector is applied.
+pub fn compute_vector_for_tree<T: fmt::Display>(
+    automaton_struct: &WeightedTreeAutomatonMatrix,
+    tree: &Tree<T>,
+) -> DynamicMatrix {
+    fn calculate_weight_rec<T: fmt::Display>(auto: &WeightedTreeAutomatonMatrix, node: &Node<T>) -> DynamicMatrix {
         let data = node.data();
         let num_children = node.degree();
         debug!(
@@ -58,7 +83,7 @@ pub fn compute_val_for_tree(
                     .clone();
                 let child = node.front().unwrap();
                 debug!(
-                    "Case 1 Children, returning val: {} * calc_value({:?})",
+                    "Case 1 Children, returning val: {} * calc_value({})",
                     val, child
                 );
                 let child_val = calculate_weight_rec(auto, child);
@@ -67,7 +92,7 @@ pub fn compute_val_for_tree(
             }
             _ => {
                 let child_iter = node.iter();
-                let child_vec: Vec<&Node<char>> = child_iter.collect();
+                let child_vec: Vec<&Node<T>> = child_iter.collect();
                 debug!(
                     "Case {num_children} Children, iterating over: {}",
                     child_vec.len()
@@ -75,9 +100,9 @@ pub fn compute_val_for_tree(
                 let weight_zero = calculate_weight_rec(auto, child_vec[0]);
                 let weight_one = calculate_weight_rec(auto, child_vec[1]);
                 let mut t_result = weight_zero.kronecker(&weight_one);
-                for child_num_x in 2..child_vec.len() {
+                for (child_num_x, child) in child_vec.iter().enumerate().skip(2) {
                     debug!("Looking at child number {child_num_x}");
-                    let weight_x = calculate_weight_rec(auto, child_vec[child_num_x]);
+                    let weight_x = calculate_weight_rec(auto, child);
                     t_result = t_result.kronecker(&weight_x);
                 }
                 let weight_self = auto
@@ -85,20 +110,118 @@ pub fn compute_val_for_tree(
                     .get(&data.to_string())
                     .unwrap()
                     .clone();
-                t_result = t_result * weight_self;
+                t_result *= weight_self;
                 return t_result;
             }
         }
     }
-    let mut result = calculate_weight_rec(&automaton_struct, tree.root());
-    result = result
-        * automaton_struct
-            .transition_matrices
-            .get("!")
-            .unwrap()
-            .clone();
-    debug!("Result: {}", result);
-    return result[(0, 0)];
+    automaton_struct.assert_bottom_up("compute_vector_for_tree");
+    return calculate_weight_rec(automaton_struct, tree.root());
+}
+
+// Computes the column vector ν(t) of a top-down automaton, that is the weight of deriving t from each state.
+// ν(σ(t_1, ..., t_k)) = ν_σ (ν(t_1) ⊗ ... ⊗ ν(t_k)), for leaves this is simply ν_σ.
+pub fn compute_top_down_vector_for_tree<T: fmt::Display>(
+    automaton_struct: &WeightedTreeAutomatonMatrix,
+    tree: &Tree<T>,
+) -> DynamicMatrix {
+    fn calculate_weight_rec<T: fmt::Display>(auto: &WeightedTreeAutomatonMatrix, node: &Node<T>) -> DynamicMatrix {
+        let ν_σ = auto.transition_matrices.get(&node.data().to_string()).unwrap();
+        // The 0-fold kronecker product is [1], so leaves simply return ν_σ.
+        let mut children = DynamicMatrix::from_element(1, 1, 1.0);
+        for child in node.iter() {
+            children = children.kronecker(&calculate_weight_rec(auto, child));
+        }
+        return ν_σ * children;
+    }
+    return calculate_weight_rec(automaton_struct, tree.root());
+}
+
+// The best run of an automaton on a tree: states has the shape of the tree and contains the index of the state of
+// each node, log_weight is the logarithm of the product of the weights of the transitions used by the run (including
+// the final weight).
+#[derive(Debug, Clone)]
+pub struct ViterbiRun {
+    pub log_weight: f64,
+    pub states: Tree<usize>,
+}
+
+// For every state q of a node the best log weight of a run on the subtree that ends in q, together with the row of
+// μ_σ (that is the tuple of child states) the best run uses.
+struct ViterbiNode {
+    best: Vec<f64>,
+    best_row: Vec<usize>,
+    children: Vec<ViterbiNode>,
+}
+
+// Computes the run with the largest weight instead of the sum of the weights of all runs, that is ||A||(t) in the
+// max-plus semiring over the logarithms of the weights:
+// best(σ(t_1, ..., t_k))_q = max_{q_1, ..., q_k} best(t_1)_q_1 + ... + best(t_k)_q_k + ln μ_σ[(q_1, ..., q_k), q].
+// The weights have to be non-negative, a weight of 0 means that the transition can't be used. Returns None if
+// there is no run with a positive weight.
+pub fn compute_viterbi_run_for_tree<T: fmt::Display>(
+    automaton_struct: &WeightedTreeAutomatonMatrix,
+    tree: &Tree<T>,
+) -> Option<ViterbiRun> {
+    let automaton_struct = automaton_struct.to_bottom_up();
+    for (σ, μ_σ) in automaton_struct.transition_matrices.iter() {
+        if μ_σ.iter().any(|weight| *weight < 0.0) {
+            panic!("The best run is only defined for automata with non-negative weights (in {σ}).");
+        }
+    }
+    let n = automaton_struct.num_states;
+
+    fn best_rec<T: fmt::Display>(auto: &WeightedTreeAutomatonMatrix, node: &Node<T>, n: usize) -> ViterbiNode {
+        let μ_σ = auto.transition_matrices.get(&node.data().to_string()).unwrap();
+        let children: Vec<ViterbiNode> = node.iter().map(|child| best_rec(auto, child, n)).collect();
+        let mut best = vec![f64::NEG_INFINITY; n];
+        let mut best_row = vec![0; n];
+        for row in 0..μ_σ.nrows() {
+            // The first child is the most significant digit of the row.
+            let mut children_weight = 0.0;
+            for (position, child) in children.iter().enumerate() {
+                let q = (row / n.pow((children.len() - 1 - position) as u32)) % n;
+                children_weight += child.best[q];
+            }
+            for q in 0..n {
+                let weight = children_weight + μ_σ[(row, q)].ln();
+                if weight > best[q] {
+                    best[q] = weight;
+                    best_row[q] = row;
+                }
+            }
+        }
+        return ViterbiNode { best, best_row, children };
+    }
+
+    fn states_rec(node: &ViterbiNode, q: usize, n: usize) -> Tree<usize> {
+        let mut states = Tree::new(q);
+        let row = node.best_row[q];
+        for (position, child) in node.children.iter().enumerate() {
+            let q_child = (row / n.pow((node.children.len() - 1 - position) as u32)) % n;
+            states.push_back(states_rec(child, q_child, n));
+        }
+        return states;
+    }
+
+    let root = best_rec(&automaton_struct, tree.root(), n);
+    let γ = automaton_struct.transition_matrices.get("!").unwrap();
+    let mut log_weight = f64::NEG_INFINITY;
+    let mut root_state = 0;
+    for q in 0..n {
+        if root.best[q] + γ[(q, 0)].ln() > log_weight {
+            log_weight = root.best[q] + γ[(q, 0)].ln();
+            root_state = q;
+        }
+    }
+    debug!("Best log weight: {log_weight}");
+    if log_weight == f64::NEG_INFINITY {
+        return None;
+    }
+    return Some(ViterbiRun {
+        log_weight,
+        states: states_rec(&root, root_state, n),
+    });
 }
 
 #[cfg(test)]
@@ -108,32 +231,33 @@ mod tests {
     #[test]
     fn simple_counter_example() {
         let struct_automaton =
-            parser::read_bottom_up_grammar("grammar/bottom_up/counter_tree");
+            parser::read_bottom_up_grammar("automata/bottom_up/counter_tree");
         let automaton = data_structures::prod_vec_to_automaton(struct_automaton);
-        let temp_tree_1 = trees::Tree::<char>::from_tuple('c');
-        let temp_tree_2 = trees::Tree::<char>::from_tuple(('a', 'c'));
-        let temp_tree_3 = trees::Tree::<char>::from_tuple(('a', ('a', 'c')));
-        let temp_tree_4 = trees::Tree::<char>::from_tuple(('b', 'c', 'c'));
-        let temp_tree_5 = trees::Tree::<char>::from_tuple(('a', ('b', 'c', 'c')));
-        let temp_tree_6 = trees::Tree::<char>::from_tuple(('a', ('b', 'c', ('a', 'c'))));
+        // The automaton counts the number of a's minus the number of b's.
+        let temp_tree_1 = trees::Tree::<char>::from_tuple('a');
+        let temp_tree_2 = trees::Tree::<char>::from_tuple('b');
+        let temp_tree_3 = trees::Tree::<char>::from_tuple(('s', 'a', 'a'));
+        let temp_tree_4 = trees::Tree::<char>::from_tuple(('s', 'a', 'b'));
+        let temp_tree_5 = trees::Tree::<char>::from_tuple(('s', ('s', 'a', 'a'), 'b'));
+        let temp_tree_6 = trees::Tree::<char>::from_tuple(('s', 'b', ('s', 'b', 'b')));
         let val_1 = compute_val_for_tree(automaton.clone(), temp_tree_1);
         let val_2 = compute_val_for_tree(automaton.clone(), temp_tree_2);
         let val_3 = compute_val_for_tree(automaton.clone(), temp_tree_3);
         let val_4 = compute_val_for_tree(automaton.clone(), temp_tree_4);
         let val_5 = compute_val_for_tree(automaton.clone(), temp_tree_5);
         let val_6 = compute_val_for_tree(automaton.clone(), temp_tree_6);
-        assert_eq!(val_1, 0.0);
-        assert_eq!(val_2, 1.0);
+        assert_eq!(val_1, 1.0);
+        assert_eq!(val_2, -1.0);
         assert_eq!(val_3, 2.0);
         assert_eq!(val_4, 0.0);
         assert_eq!(val_5, 1.0);
-        assert_eq!(val_6, 2.0);
+        assert_eq!(val_6, -3.0);
         
     }
     #[test]
     fn simple_computation_example() {
         let struct_automaton =
-            parser::read_bottom_up_grammar("grammar/bottom_up/computation_tree");
+            parser::read_bottom_up_grammar("automata/bottom_up/computation_tree");
         let automaton = data_structures::prod_vec_to_automaton(struct_automaton);
         
         let temp_tree_1 = trees::Tree::<char>::from_tuple('0');
@@ -164,7 +288,7 @@ mod tests {
     #[test]
     fn simple_rgb_example() {
         let struct_automaton =
-            parser::read_bottom_up_grammar("grammar/bottom_up/rgb_tree");
+            parser::read_bottom_up_grammar("automata/bottom_up/rgb_tree");
         let automaton = data_structures::prod_vec_to_automaton(struct_automaton);
 
         let temp_tree_1 = trees::Tree::<char>::from_tuple('R');
@@ -186,4 +310,99 @@ mod tests {
         assert_eq!(val_5, 256000000.0);
         assert_eq!(val_6, 128064064.0);
     }
+    #[test]
+    fn top_down_pcfg_example() {
+        let grammar = parser::read_top_down_automaton(
+            "automata/top_down/simple_pcfg.grammar",
+            "automata/top_down/simple_pcfg.lexicon",
+        );
+        let top_down = data_structures::prod_vec_to_top_down_automaton(&grammar, "ROOT_0");
+        let bottom_up = top_down.to_bottom_up();
+        // ROOT(S(NP/1(dog), VP(saw, NP/2(the, telescope)))) = 1 * 1 * (0.3 * 0.6) * 0.6 * (0.5 * 1 * 0.4)
+        let mut np_1 = Tree::new("NP/1".to_owned());
+        np_1.push_back(Tree::new("dog".to_owned()));
+        let mut np_2 = Tree::new("NP/2".to_owned());
+        np_2.push_back(Tree::new("the".to_owned()));
+        np_2.push_back(Tree::new("telescope".to_owned()));
+        let mut vp = Tree::new("VP".to_owned());
+        vp.push_back(Tree::new("saw".to_owned()));
+        vp.push_back(np_2);
+        let mut s = Tree::new("S".to_owned());
+        s.push_back(np_1);
+        s.push_back(vp);
+        let mut root = Tree::new("ROOT".to_owned());
+        root.push_back(s);
+        let expected = 0.3 * 0.6 * 0.6 * 0.5 * 0.4;
+        assert!((compute_val_for_tree(top_down.clone(), root.clone()) - expected).abs() < 1e-12);
+        assert!((compute_val_for_tree(bottom_up.clone(), root.clone()) - expected).abs() < 1e-12);
+
+        // Both representations can be converted into each other and minimised.
+        assert_eq!(bottom_up.to_top_down().transition_matrices, top_down.transition_matrices);
+        let minimised = crate::minimisation::minimize_automaton(top_down.clone());
+        assert!(matches!(minimised.grammar_type, data_structures::GrammarType::TopDown));
+        assert!(minimised.num_states <= top_down.num_states);
+        assert!((compute_val_for_tree(minimised, root.clone()) - expected).abs() < 1e-9);
+
+        // The other conversions keep the representation or go through the bottom-up form.
+        let p = DynamicMatrix::from_fn(top_down.num_states, top_down.num_states, |i, j| {
+            if i == j {
+                return 2.0;
+            }
+            if j == i + 1 {
+                return 1.0;
+            }
+            return 0.0;
+        });
+        let changed = top_down.change_basis(&p);
+        assert!(matches!(changed.grammar_type, data_structures::GrammarType::TopDown));
+        assert!((compute_val_for_tree(changed, root.clone()) - expected).abs() < 1e-12);
+        let round_trip = data_structures::prod_vec_to_automaton(data_structures::automaton_to_prod_vec(&top_down));
+        assert!((compute_val_for_tree(round_trip, root) - expected).abs() < 1e-12);
+    }
+
+    #[test]
+    #[should_panic(expected = "expects a bottom-up automaton")]
+    fn top_down_struct_needs_root_state() {
+        let grammar = parser::read_top_down_automaton(
+            "automata/top_down/simple_pcfg.grammar",
+            "automata/top_down/simple_pcfg.lexicon",
+        );
+        data_structures::prod_vec_to_automaton(grammar);
+    }
+
+    #[test]
+    fn viterbi_split_pcfg() {
+        // NP has the two latent states NP_0 and NP_1, so the tree has several runs.
+        let grammar = parser::read_top_down_automaton(
+            "automata/top_down/split_pcfg.grammar",
+            "automata/top_down/split_pcfg.lexicon",
+        );
+        let states = data_structures::ordered_states(&grammar);
+        let automaton = data_structures::prod_vec_to_top_down_automaton(&grammar, "ROOT_0");
+        let leaf = |label: &str| Tree::new(label.to_owned());
+        let mut np_1 = Tree::new("NP/2".to_owned());
+        np_1.push_back(leaf("the"));
+        np_1.push_back(leaf("dog"));
+        let mut np_2 = Tree::new("NP/1".to_owned());
+        np_2.push_back(leaf("telescope"));
+        let mut vp = Tree::new("VP".to_owned());
+        vp.push_back(leaf("saw"));
+        vp.push_back(np_2);
+        let mut s = Tree::new("S".to_owned());
+        s.push_back(np_1);
+        s.push_back(vp);
+        let mut root = Tree::new("ROOT".to_owned());
+        root.push_back(s);
+
+        let run = compute_viterbi_run_for_tree(&automaton, &root).unwrap();
+        // S_0 -> NP_0 VP_0, NP_0 -> DT_0 NN_0, VP_0 -> VB_0 NP_1 and NP_1 -> NN_0.
+        let expected = 0.5 * (0.6 * 0.6) * (0.3 * 0.4 * 0.4);
+        assert!((run.log_weight - f64::ln(expected)).abs() < 1e-12);
+        assert!(expected < compute_val_for_tree(automaton.clone(), root.clone()));
+        let names: Vec<&str> = run.states.bfs().iter.map(|visit| states[*visit.data].as_str()).collect();
+        assert_eq!(
+            names,
+            ["ROOT_0", "S_0", "NP_0", "VP_0", "DT_0", "NN_0", "VB_0", "NP_1", "NN_0"]
+        );
+    }
 }
@@ -3,9 +3,10 @@
 use log::{debug, error, info};
 use nalgebra::DMatrix;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     fmt,
 };
+use trees::{Node, Tree};
 
 type DynamicMatrix = DMatrix<f64>;
 
@@ -17,20 +18,102 @@ pub enum GrammarType {
 }
 
 // "!" -> This is the Index of the final weight vector in the case of a bottom-up automaton.
-#[derive(Debug)]
+// In the case of a top-down automaton it is the index of the initial weight vector.
+// The ranked alphabet and the transitions are kept in BTreeMaps, so that every iteration over the alphabet happens
+// in the same (sorted) order. Otherwise the rows chosen in step I, the resulting basis and the printed automata would
+// differ between runs.
+#[derive(Debug, Clone)]
 pub struct WeightedTreeAutomatonStruct {
     pub num_states: usize,
     pub grammar_type: GrammarType,
-    pub ranked_alphabet: HashMap<String, usize>,
-    pub transition_structs: HashMap<String, Vec<Production>>,
+    pub ranked_alphabet: BTreeMap<String, usize>,
+    pub transition_structs: BTreeMap<String, Vec<Production>>,
 }
 
+// Bottom-up: μ_σ has the shape n^k × n and γ = transition_matrices["!"] the shape n × 1. The weight of a tree is
+// μ(t) γ with the row vector μ(σ(t_1, ..., t_k)) = (μ(t_1) ⊗ ... ⊗ μ(t_k)) μ_σ.
+// Top-down: ν_σ has the shape n × n^k, where ν_σ[q, (q_1, ..., q_k)] is the weight of q -> σ(q_1, ..., q_k), and the
+// initial weight vector ι = transition_matrices["!"] the shape 1 × n. The weight of a tree is ι ν(t) with the column
+// vector ν(σ(t_1, ..., t_k)) = ν_σ (ν(t_1) ⊗ ... ⊗ ν(t_k)).
+// Both representations describe the same automaton if ν_σ = μ_σ^T and ι = γ^T.
 #[derive(Debug, Clone)]
 pub struct WeightedTreeAutomatonMatrix {
     pub num_states: usize,
     pub grammar_type: GrammarType,
-    pub ranked_alphabet: HashMap<String, usize>,
-    pub transition_matrices: HashMap<String, DynamicMatrix>,
+    pub ranked_alphabet: BTreeMap<String, usize>,
+    pub transition_matrices: BTreeMap<String, DynamicMatrix>,
+}
+
+impl WeightedTreeAutomatonMatrix {
+    // Transposes all matrices, which turns a bottom-up automaton into the equivalent top-down automaton and the
+    // other way round.
+    fn transposed(&self, grammar_type: GrammarType) -> WeightedTreeAutomatonMatrix {
+        return WeightedTreeAutomatonMatrix {
+            num_states: self.num_states,
+            grammar_type,
+            ranked_alphabet: self.ranked_alphabet.clone(),
+            transition_matrices: self
+                .transition_matrices
+                .iter()
+                .map(|(symbol, matrix)| (symbol.clone(), matrix.transpose()))
+                .collect(),
+        };
+    }
+
+    pub fn to_top_down(&self) -> WeightedTreeAutomatonMatrix {
+        match self.grammar_type {
+            GrammarType::TopDown => return self.clone(),
+            GrammarType::BottomUp => return self.transposed(GrammarType::TopDown),
+        }
+    }
+
+    pub fn to_bottom_up(&self) -> WeightedTreeAutomatonMatrix {
+        match self.grammar_type {
+            GrammarType::BottomUp => return self.clone(),
+            GrammarType::TopDown => return self.transposed(GrammarType::BottomUp),
+        }
+    }
+
+    // Most algorithms work on the bottom-up matrices only. They call this first, so that a top-down automaton leads
+    // to a clear panic instead of wrong results.
+    pub fn assert_bottom_up(&self, function: &str) {
+        if let GrammarType::TopDown = self.grammar_type {
+            panic!("{function} expects a bottom-up automaton, convert top-down automata with to_bottom_up first.");
+        }
+    }
+
+    // Applies the invertible matrix P to the state space of the automaton (panics if P is singular):
+    // μ_σ ↦ (P^{-1})^{⊗k} μ_σ P and γ ↦ P^{-1} γ with k = rk(σ).
+    // The resulting automaton computes μ'(t) = μ(t) P for every tree t, so ||A||(t) stays the same.
+    // Top-down automata are changed in their bottom-up form and transposed back.
+    pub fn change_basis(&self, p: &DynamicMatrix) -> WeightedTreeAutomatonMatrix {
+        if let GrammarType::TopDown = self.grammar_type {
+            return self.to_bottom_up().change_basis(p).to_top_down();
+        }
+        let p_inverse = p
+            .clone()
+            .try_inverse()
+            .expect("change_basis needs an invertible matrix P, but the given matrix is singular.");
+        let mut transition_matrices = BTreeMap::new();
+        for (symbol, μ_σ) in self.transition_matrices.iter() {
+            if symbol == "!" {
+                transition_matrices.insert(symbol.clone(), &p_inverse * μ_σ);
+                continue;
+            }
+            // The 0-fold kronecker product is [1], so leaves are simply multiplied with P.
+            let mut p_inverse_kron_k = DynamicMatrix::from_element(1, 1, 1.0);
+            for _ in 0..*self.ranked_alphabet.get(symbol).unwrap() {
+                p_inverse_kron_k = p_inverse_kron_k.kronecker(&p_inverse);
+            }
+            transition_matrices.insert(symbol.clone(), p_inverse_kron_k * μ_σ * p);
+        }
+        return WeightedTreeAutomatonMatrix {
+            num_states: self.num_states,
+            grammar_type: self.grammar_type.clone(),
+            ranked_alphabet: self.ranked_alphabet.clone(),
+            transition_matrices,
+        };
+    }
 }
 
 impl fmt::Display for WeightedTreeAutomatonMatrix {
@@ -77,7 +160,7 @@ impl fmt::Display for WeightedTreeAutomatonStruct {
 // We need to use vectors inside the production, because we either look at bottom-up, in which case we need to
 // encode more than one left side state, or in the case of top-down we are look at multiple right side states.
 // There might be an argument to not save the root_symbol separately, but instead save it in the overlaying data structure:
-// HashMap<String, Vec<data_structures::Production>>,
+// BTreeMap<String, Vec<data_structures::Production>>,
 // but this way the Production struct can be used by itself.
 pub struct Production {
     pub root_symbol: String,
@@ -96,17 +179,49 @@ impl fmt::Display for Production {
     }
 }
 
-pub fn prod_vec_to_automaton(
-    automaton_struct: WeightedTreeAutomatonStruct,
-) -> WeightedTreeAutomatonMatrix {
-    debug!("Complete automaton: {:?}", automaton_struct);
-    debug!("Converting Bottom-Up");
-    let mut transition_matrices: HashMap<String, DynamicMatrix> = HashMap::new();
+// Contexts are trees in which exactly one leaf is labelled with CONTEXT_HOLE.
+pub const CONTEXT_HOLE: &str = "□";
 
-    // Ordering of states is always expected to be alpha-numerical.
-    // Here we create a hashmap that contains the index of each state if states are ordered alphanumerical.
+// Computes c[t], that is the tree we get by replacing the hole of the context c with t.
+// If t is a context itself, the result is again a context whose hole is the hole of t.
+pub fn plug_into_context(context: &Tree<String>, tree: &Tree<String>) -> Tree<String> {
+    fn plug_rec(node: &Node<String>, tree: &Tree<String>) -> Tree<String> {
+        if node.data() == CONTEXT_HOLE {
+            return tree.clone();
+        }
+        let mut new_tree = Tree::new(node.data().clone());
+        for child in node.iter() {
+            new_tree.push_back(plug_rec(child, tree));
+        }
+        return new_tree;
+    }
+    return plug_rec(context.root(), tree);
+}
+
+// Derives the ranked alphabet from a collection of trees: each label is mapped to the number of children of the
+// nodes it appears at. A label that appears with different numbers of children can't be part of a ranked alphabet.
+pub fn ranked_alphabet_from_trees(trees: &[Tree<String>]) -> BTreeMap<String, usize> {
+    fn collect_rec(node: &Node<String>, ranked_alphabet: &mut BTreeMap<String, usize>) {
+        let rank = ranked_alphabet.entry(node.data().clone()).or_insert(node.degree());
+        if *rank != node.degree() {
+            panic!("The symbol {} appears with rank {} and rank {}.", node.data(), rank, node.degree());
+        }
+        for child in node.iter() {
+            collect_rec(child, ranked_alphabet);
+        }
+    }
+    let mut ranked_alphabet = BTreeMap::new();
+    for tree in trees {
+        collect_rec(tree.root(), &mut ranked_alphabet);
+    }
+    return ranked_alphabet;
+}
+
+// Ordering of states is always expected to be alpha-numerical, the index of a state in the matrices is its position
+// in this list.
+pub fn ordered_states(automaton_struct: &WeightedTreeAutomatonStruct) -> Vec<String> {
     let mut unordered_state_set = HashSet::new();
-    for prod_vec in automaton_struct.transition_structs.values().into_iter() {
+    for prod_vec in automaton_struct.transition_structs.values() {
         for prod in prod_vec.iter() {
             for left_state in prod.left_states.clone() {
                 unordered_state_set.insert(left_state);
@@ -119,9 +234,27 @@ pub fn prod_vec_to_automaton(
 
     let mut ordered_state_list: Vec<String> = unordered_state_set.into_iter().collect();
     ordered_state_list.sort();
+    return ordered_state_list;
+}
+
+// Builds the bottom-up matrices of a bottom-up automaton. The productions of a top-down grammar have their states
+// on the other side and it has no final weights, so those have to go through top_down_to_bottom_up (or
+// prod_vec_to_top_down_automaton) with a root state first.
+pub fn prod_vec_to_automaton(
+    automaton_struct: WeightedTreeAutomatonStruct,
+) -> WeightedTreeAutomatonMatrix {
+    if let GrammarType::TopDown = automaton_struct.grammar_type {
+        panic!("prod_vec_to_automaton expects a bottom-up automaton, convert top-down grammars with top_down_to_bottom_up first.");
+    }
+    debug!("Complete automaton: {:?}", automaton_struct);
+    debug!("Converting Bottom-Up");
+    let mut transition_matrices: BTreeMap<String, DynamicMatrix> = BTreeMap::new();
+
+    // Here we create a hashmap that contains the index of each state if states are ordered alphanumerical.
+    let ordered_state_list = ordered_states(&automaton_struct);
     let mut ordered_state_hashmap = HashMap::new();
-    for i in 0..ordered_state_list.len() {
-        ordered_state_hashmap.insert(ordered_state_list[i].clone(), i);
+    for (i, state) in ordered_state_list.iter().enumerate() {
+        ordered_state_hashmap.insert(state.clone(), i);
     }
 
     for element in automaton_struct.ranked_alphabet.clone() {
@@ -203,9 +336,142 @@ pub fn prod_vec_to_automaton(
 
     let automaton_matrix = WeightedTreeAutomatonMatrix {
         num_states: automaton_struct.num_states,
-        grammar_type: automaton_struct.grammar_type,
+        grammar_type: GrammarType::BottomUp,
         ranked_alphabet: automaton_struct.ranked_alphabet,
         transition_matrices,
     };
     return automaton_matrix;
 }
+
+// Turns a top-down automaton (for example a grammar read by read_top_down_automaton) into the equivalent bottom-up
+// automaton: A -> σ(B, C) becomes σ(B, C) -> A and the root state gets the final weight 1.
+// A ranked alphabet needs a fixed rank per symbol, so symbols which appear with more than one rank (for example NP
+// in NP -> DT NN and NP -> NN) are split into one symbol per rank, named "σ/k".
+pub fn top_down_to_bottom_up(
+    automaton_struct: &WeightedTreeAutomatonStruct,
+    root_state: &str,
+) -> WeightedTreeAutomatonStruct {
+    let mut ranks: BTreeMap<String, HashSet<usize>> = BTreeMap::new();
+    for prod in automaton_struct.transition_structs.values().flatten() {
+        ranks
+            .entry(prod.root_symbol.clone())
+            .or_default()
+            .insert(prod.right_states.len());
+    }
+
+    let mut ranked_alphabet = BTreeMap::new();
+    let mut transition_structs: BTreeMap<String, Vec<Production>> = BTreeMap::new();
+    for prod in automaton_struct.transition_structs.values().flatten() {
+        let rank = prod.right_states.len();
+        let mut symbol = prod.root_symbol.clone();
+        if ranks.get(&symbol).unwrap().len() > 1 {
+            symbol = format!("{symbol}/{rank}");
+        }
+        ranked_alphabet.insert(symbol.clone(), rank);
+        let production = Production {
+            root_symbol: symbol.clone(),
+            left_states: prod.right_states.clone(),
+            right_states: prod.left_states.clone(),
+            cost: prod.cost,
+        };
+        transition_structs.entry(symbol).or_default().push(production);
+    }
+
+    let final_production = Production {
+        root_symbol: "!".to_owned(),
+        left_states: vec![root_state.to_owned()],
+        right_states: vec![],
+        cost: 1.0,
+    };
+    transition_structs.insert("!".to_owned(), vec![final_production]);
+    ranked_alphabet.insert("!".to_owned(), automaton_struct.num_states);
+
+    return WeightedTreeAutomatonStruct {
+        num_states: automaton_struct.num_states,
+        grammar_type: GrammarType::BottomUp,
+        ranked_alphabet,
+        transition_structs,
+    };
+}
+
+// Turns a top-down automaton (for example a grammar read by read_top_down_automaton) into its top-down matrix
+// representation, in which only the root state has an initial weight (of 1). Symbols which appear with more than
+// one rank are split as in top_down_to_bottom_up.
+pub fn prod_vec_to_top_down_automaton(
+    automaton_struct: &WeightedTreeAutomatonStruct,
+    root_state: &str,
+) -> WeightedTreeAutomatonMatrix {
+    return prod_vec_to_automaton(top_down_to_bottom_up(automaton_struct, root_state)).to_top_down();
+}
+
+// Writes a tree in bracket notation: leaves are written as their label, every other node as
+// (label child_1 ... child_k), for example (s a (g b)).
+pub fn tree_to_brackets(tree: &Tree<String>) -> String {
+    fn brackets_rec(node: &Node<String>) -> String {
+        if node.degree() == 0 {
+            return node.data().clone();
+        }
+        let mut result = format!("({}", node.data());
+        for child in node.iter() {
+            result.push(' ');
+            result.push_str(&brackets_rec(child));
+        }
+        result.push(')');
+        return result;
+    }
+    return brackets_rec(tree.root());
+}
+
+// The inverse of prod_vec_to_automaton for bottom-up automata: every non-zero entry μ_σ[(q_1, ..., q_k), q] becomes
+// the production σ q_1 ... q_k -> q. The states are called q0, q1, ... and padded with zeros, so that their
+// alpha-numerical order is the order of the indices. The final weights of all states are kept, even if they are 0,
+// so that every state appears in at least one production. Top-down automata are transposed first, so the result
+// is always a bottom-up automaton.
+pub fn automaton_to_prod_vec(automaton_matrix: &WeightedTreeAutomatonMatrix) -> WeightedTreeAutomatonStruct {
+    let automaton_matrix = &automaton_matrix.to_bottom_up();
+    let n = automaton_matrix.num_states;
+    let width = n.saturating_sub(1).to_string().len();
+    let state_name = |q: usize| format!("q{q:0width$}");
+
+    let mut transition_structs: BTreeMap<String, Vec<Production>> = BTreeMap::new();
+    for (σ, μ_σ) in automaton_matrix.transition_matrices.iter() {
+        let mut productions = Vec::new();
+        if σ == "!" {
+            for q in 0..n {
+                productions.push(Production {
+                    root_symbol: σ.clone(),
+                    left_states: vec![state_name(q)],
+                    right_states: vec![],
+                    cost: μ_σ[(q, 0)],
+                });
+            }
+            transition_structs.insert(σ.clone(), productions);
+            continue;
+        }
+        let rk_σ = *automaton_matrix.ranked_alphabet.get(σ).unwrap();
+        for row in 0..μ_σ.nrows() {
+            // The first left state is the most significant digit of the row index.
+            let left_states: Vec<String> = (0..rk_σ)
+                .map(|position| state_name((row / n.pow((rk_σ - 1 - position) as u32)) % n))
+                .collect();
+            for q in 0..n {
+                if μ_σ[(row, q)] != 0.0 {
+                    productions.push(Production {
+                        root_symbol: σ.clone(),
+                        left_states: left_states.clone(),
+                        right_states: vec![state_name(q)],
+                        cost: μ_σ[(row, q)],
+                    });
+                }
+            }
+        }
+        transition_structs.insert(σ.clone(), productions);
+    }
+
+    return WeightedTreeAutomatonStruct {
+        num_states: n,
+        grammar_type: GrammarType::BottomUp,
+        ranked_alphabet: automaton_matrix.ranked_alphabet.clone(),
+        transition_structs,
+    };
+}
@@ -0,0 +1,210 @@
+use crate::data_structures::{
+    ordered_states, prod_vec_to_automaton, top_down_to_bottom_up, GrammarType, Production,
+    WeightedTreeAutomatonMatrix, WeightedTreeAutomatonStruct,
+};
+use crate::inside_outside::{expected_counts, forest_weight, Forest};
+use nalgebra::DMatrix;
+use log::{info, log_enabled, warn, Level};
+use std::collections::{BTreeMap, HashMap};
+use trees::Tree;
+
+type DynamicMatrix = DMatrix<f64>;
+
+/*
+    Expectation-maximisation training of the weights of an automaton on a corpus of trees, with the rule skeleton
+    kept fixed: productions with cost 0 keep the cost 0 and no productions are added.
+    - E-step: the expected number of uses of every production in the runs on the corpus trees, computed with the
+      inside and outside weights (see inside_outside.rs).
+    - M-step: the productions are grouped by their target state (the left side state of a top-down production) and
+      the new cost of a production is its expected count divided by the expected count of its group. The final
+      weights form one group on their own.
+    So the trained automaton is a probabilistic grammar, every iteration doesn't decrease the likelihood of the
+    corpus. The states don't have to be visible in the trees, which allows training latent-annotation grammars
+    (e.g. NP_0 and NP_1 for the symbol NP), the result can then be compressed with the minimisation.
+*/
+
+// The sum of the logarithms of the weights of the corpus trees.
+pub fn log_likelihood(automaton_matrix: &WeightedTreeAutomatonMatrix, corpus: &[Tree<String>]) -> f64 {
+    return corpus
+        .iter()
+        .map(|tree| forest_weight(automaton_matrix, &Forest::from_tree(tree)).ln())
+        .sum();
+}
+
+// The entry of the production in the matrices of prod_vec_to_automaton.
+fn matrix_entry(production: &Production, state_index: &HashMap<String, usize>, n: usize) -> (usize, usize) {
+    let mut x = 0;
+    for state in production.left_states.iter() {
+        x = x * n + state_index.get(state).unwrap();
+    }
+    let y = match production.right_states.first() {
+        Some(state) => *state_index.get(state).unwrap(),
+        None => 0,
+    };
+    return (x, y);
+}
+
+// One iteration of EM on a bottom-up automaton.
+fn em_iteration(automaton_struct: &WeightedTreeAutomatonStruct, corpus: &[Tree<String>]) -> WeightedTreeAutomatonStruct {
+    let automaton_matrix = prod_vec_to_automaton(automaton_struct.clone());
+    let n = automaton_matrix.num_states;
+
+    // E-step
+    let mut counts: BTreeMap<String, DynamicMatrix> = BTreeMap::new();
+    for tree in corpus {
+        let forest = Forest::from_tree(tree);
+        if forest_weight(&automaton_matrix, &forest) <= 0.0 {
+            warn!("Skipping a tree without a run of positive weight: {tree}");
+            continue;
+        }
+        for (σ, count) in expected_counts(&automaton_matrix, &forest) {
+            counts
+                .entry(σ)
+                .and_modify(|sum| *sum += &count)
+                .or_insert(count);
+        }
+    }
+
+    // M-step
+    let state_index: HashMap<String, usize> = ordered_states(automaton_struct)
+        .into_iter()
+        .enumerate()
+        .map(|(i, state)| (state, i))
+        .collect();
+    let group = |production: &Production| match production.right_states.first() {
+        Some(state) => state.clone(),
+        None => "!".to_owned(),
+    };
+    let count = |production: &Production| match counts.get(&production.root_symbol) {
+        Some(count) => count[matrix_entry(production, &state_index, n)],
+        None => 0.0,
+    };
+    let mut group_counts: HashMap<String, f64> = HashMap::new();
+    for production in automaton_struct.transition_structs.values().flatten() {
+        *group_counts.entry(group(production)).or_insert(0.0) += count(production);
+    }
+
+    let mut trained = automaton_struct.clone();
+    for production in trained.transition_structs.values_mut().flatten() {
+        let group_count = group_counts.get(&group(production)).unwrap();
+        // A group which isn't used by the corpus keeps its costs.
+        if *group_count > 0.0 {
+            production.cost = count(production) / group_count;
+        }
+    }
+    return trained;
+}
+
+// Trains the costs of a bottom-up automaton for the given number of iterations.
+pub fn em_training(
+    automaton_struct: &WeightedTreeAutomatonStruct,
+    corpus: &[Tree<String>],
+    iterations: usize,
+) -> WeightedTreeAutomatonStruct {
+    if let GrammarType::TopDown = automaton_struct.grammar_type {
+        panic!("em_training expects a bottom-up automaton, use em_training_top_down for top-down grammars.");
+    }
+    let mut trained = automaton_struct.clone();
+    for iteration in 0..iterations {
+        trained = em_iteration(&trained, corpus);
+        // Computing the likelihood costs another pass over the corpus, so it is only done if it is logged.
+        if !log_enabled!(Level::Info) {
+            continue;
+        }
+        let likelihood = log_likelihood(&prod_vec_to_automaton(trained.clone()), corpus);
+        info!("EM iteration {}: log-likelihood {likelihood}", iteration + 1);
+    }
+    return trained;
+}
+
+// Trains the costs of a top-down grammar (for example read by read_top_down_automaton) with the given root state.
+// The corpus trees use the symbols of top_down_to_bottom_up, so symbols with several ranks are named "σ/k".
+pub fn em_training_top_down(
+    grammar: &WeightedTreeAutomatonStruct,
+    root_state: &str,
+    corpus: &[Tree<String>],
+    iterations: usize,
+) -> WeightedTreeAutomatonStruct {
+    let bottom_up = em_training(&top_down_to_bottom_up(grammar, root_state), corpus, iterations);
+
+    // top_down_to_bottom_up keeps the order of the productions, so the i-th production of a symbol in the bottom-up
+    // automaton is the i-th top-down production that was turned into that symbol.
+    let mut used: HashMap<String, usize> = HashMap::new();
+    let mut trained = grammar.clone();
+    for production in trained.transition_structs.values_mut().flatten() {
+        let rank = production.right_states.len();
+        let mut symbol = production.root_symbol.clone();
+        if bottom_up.ranked_alphabet.get(&symbol) != Some(&rank) {
+            symbol = format!("{symbol}/{rank}");
+        }
+        let index = used.entry(symbol.clone()).or_insert(0);
+        production.cost = bottom_up.transition_structs.get(&symbol).unwrap()[*index].cost;
+        *index += 1;
+    }
+    return trained;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+    use crate::sampling::TreeSampler;
+
+    fn costs(grammar: &WeightedTreeAutomatonStruct, symbol: &str) -> Vec<f64> {
+        return grammar.transition_structs.get(symbol).unwrap().iter().map(|prod| prod.cost).collect();
+    }
+
+    fn sample_corpus(size: usize) -> Vec<Tree<String>> {
+        let grammar = parser::read_top_down_automaton(
+            "automata/top_down/simple_pcfg.grammar",
+            "automata/top_down/simple_pcfg.lexicon",
+        );
+        let mut sampler = TreeSampler::new(&prod_vec_to_automaton(top_down_to_bottom_up(&grammar, "ROOT_0")), 0);
+        return (0..size).map(|_| sampler.sample()).collect();
+    }
+
+    #[test]
+    fn em_recovers_pcfg() {
+        let mut grammar = parser::read_top_down_automaton(
+            "automata/top_down/simple_pcfg.grammar",
+            "automata/top_down/simple_pcfg.lexicon",
+        );
+        let original = grammar.clone();
+        for production in grammar.transition_structs.values_mut().flatten() {
+            production.cost = 0.5;
+        }
+        let trained = em_training_top_down(&grammar, "ROOT_0", &sample_corpus(500), 1);
+        // The states are visible in the trees, so one iteration gives the relative frequencies.
+        for symbol in ["NP", "VP", "dog", "telescope"] {
+            for (cost, expected) in costs(&trained, symbol).iter().zip(costs(&original, symbol)) {
+                assert!((cost - expected).abs() < 0.05);
+            }
+        }
+    }
+
+    #[test]
+    fn em_latent_annotations() {
+        let grammar = parser::read_top_down_automaton(
+            "automata/top_down/split_pcfg.grammar",
+            "automata/top_down/split_pcfg.lexicon",
+        );
+        let corpus = sample_corpus(50);
+        let mut trained = grammar.clone();
+        let mut likelihood = f64::NEG_INFINITY;
+        for _ in 0..3 {
+            trained = em_training_top_down(&trained, "ROOT_0", &corpus, 1);
+            let automaton = prod_vec_to_automaton(top_down_to_bottom_up(&trained, "ROOT_0"));
+            let new_likelihood = log_likelihood(&automaton, &corpus);
+            assert!(new_likelihood >= likelihood - 1e-9);
+            likelihood = new_likelihood;
+        }
+        // The costs of the productions of every state still sum to 1.
+        let mut sums: HashMap<String, f64> = HashMap::new();
+        for production in trained.transition_structs.values().flatten() {
+            *sums.entry(production.left_states[0].clone()).or_insert(0.0) += production.cost;
+        }
+        for sum in sums.values() {
+            assert!((sum - 1.0).abs() < 1e-9);
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use crate::data_structures::{GrammarType, WeightedTreeAutomatonMatrix};
+use crate::step_i::step_i_forward_with_trees;
+use nalgebra::DMatrix;
+use std::collections::BTreeMap;
+use log::debug;
+use trees::Tree;
+
+type DynamicMatrix = DMatrix<f64>;
+
+// All singular values below eps are considered equal to 0 when used in numeric algorithms.
+const EPS: f64 = 0.00001;
+
+/*
+    Two automata A and B are equivalent if ||A||(t) = ||B||(t) for all trees t. This is the case if and only if
+    their difference automaton D with ||D|| = ||A|| - ||B|| recognises the zero series, which in turn is the case
+    if and only if every vector of the forward space of D is orthogonal to the final weight vector of D.
+    Since step I also records the spanning tree for every row of F, a row with μ(t) γ != 0 directly gives us a
+    tree t on which A and B differ.
+*/
+
+// Builds the automaton D with ||D||(t) = ||a||(t) - ||b||(t) as the direct sum of a and b:
+// The states of a come first, followed by the states of b. Transitions that mix states of a and b have weight 0.
+pub fn difference_automaton(
+    a: &WeightedTreeAutomatonMatrix,
+    b: &WeightedTreeAutomatonMatrix,
+) -> WeightedTreeAutomatonMatrix {
+    a.assert_bottom_up("difference_automaton");
+    b.assert_bottom_up("difference_automaton");
+    let n_a = a.num_states;
+    let n_b = b.num_states;
+    let n = n_a + n_b;
+    let mut transition_matrices = BTreeMap::new();
+    let mut ranked_alphabet = a.ranked_alphabet.clone();
+    for (σ, rk_σ) in b.ranked_alphabet.iter() {
+        let rank = *ranked_alphabet.entry(σ.clone()).or_insert(*rk_σ);
+        if rank != *rk_σ && σ != "!" {
+            panic!("The symbol {} has rank {} in the first automaton and rank {} in the second.", σ, rank, rk_σ);
+        }
+    }
+
+    for (σ, rk_σ) in ranked_alphabet.iter() {
+        if σ == "!" {
+            continue;
+        }
+        let mut μ_σ = DynamicMatrix::from_element(n.pow(*rk_σ as u32), n, 0.0);
+        // Copy both automata into their block, a symbol that only one automaton knows simply has weight 0 in the other.
+        for (automaton, offset, num_states) in [(a, 0, n_a), (b, n_a, n_b)] {
+            let old_μ_σ = match automaton.transition_matrices.get(σ) {
+                Some(old_μ_σ) => old_μ_σ,
+                None => continue,
+            };
+            for old_row in 0..old_μ_σ.nrows() {
+                // Decode the state tuple of the row and re-encode it with the shifted states of D.
+                let mut new_row = 0;
+                for position in 0..*rk_σ {
+                    let q = (old_row / num_states.pow((*rk_σ - 1 - position) as u32)) % num_states;
+                    new_row = new_row * n + q + offset;
+                }
+                for column in 0..num_states {
+                    μ_σ[(new_row, column + offset)] = old_μ_σ[(old_row, column)];
+                }
+            }
+        }
+        transition_matrices.insert(σ.clone(), μ_σ);
+    }
+
+    let mut γ = DynamicMatrix::from_element(n, 1, 0.0);
+    let γ_a = a.transition_matrices.get("!").unwrap();
+    let γ_b = b.transition_matrices.get("!").unwrap();
+    for index in 0..n_a {
+        γ[(index, 0)] = γ_a[(index, 0)];
+    }
+    for index in 0..n_b {
+        γ[(index + n_a, 0)] = -γ_b[(index, 0)];
+    }
+    transition_matrices.insert("!".to_owned(), γ);
+    ranked_alphabet.insert("!".to_owned(), n);
+
+    return WeightedTreeAutomatonMatrix {
+        num_states: n,
+        grammar_type: GrammarType::BottomUp,
+        ranked_alphabet,
+        transition_matrices,
+    };
+}
+
+// Returns a tree t with ||a||(t) != ||b||(t), or None if both automata are equivalent. The automata can be bottom-up
+// or top-down.
+pub fn find_counterexample(
+    a: &WeightedTreeAutomatonMatrix,
+    b: &WeightedTreeAutomatonMatrix,
+) -> Option<Tree<String>> {
+    let d = difference_automaton(&a.to_bottom_up(), &b.to_bottom_up());
+    let (f, spanning_trees) = step_i_forward_with_trees(&d);
+    let γ = d.transition_matrices.get("!").unwrap();
+    let weights = &f * γ;
+    for (row_num, tree) in spanning_trees.into_iter().enumerate() {
+        if weights[(row_num, 0)].abs() > EPS {
+            debug!("Counterexample {tree} with difference {}", weights[(row_num, 0)]);
+            return Some(tree);
+        }
+    }
+    return None;
+}
+
+pub fn are_equivalent(a: &WeightedTreeAutomatonMatrix, b: &WeightedTreeAutomatonMatrix) -> bool {
+    return find_counterexample(a, b).is_none();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{computation, data_structures, parser};
+
+    #[test]
+    fn equivalence_counter_example() {
+        let automaton = data_structures::prod_vec_to_automaton(
+            parser::read_bottom_up_grammar("automata/bottom_up/counter_tree"),
+        );
+        let automaton_two = data_structures::prod_vec_to_automaton(
+            parser::read_bottom_up_grammar("automata/bottom_up/counter_tree_times_two"),
+        );
+        assert!(are_equivalent(&automaton, &automaton_two));
+
+        let mut automaton_three = automaton_two.clone();
+        let μ_b = automaton_three.transition_matrices.get_mut("b").unwrap();
+        *μ_b *= 2.0;
+        let counterexample = find_counterexample(&automaton, &automaton_three).unwrap();
+        let val = computation::compute_val_for_tree(automaton.clone(), counterexample.clone());
+        let val_three = computation::compute_val_for_tree(automaton_three, counterexample);
+        assert!((val - val_three).abs() > EPS);
+    }
+
+    #[test]
+    #[should_panic(expected = "The symbol a has rank 0 in the first automaton and rank 1 in the second.")]
+    fn difference_automaton_rank_mismatch() {
+        let automaton = data_structures::prod_vec_to_automaton(
+            parser::read_bottom_up_grammar("automata/bottom_up/counter_tree"),
+        );
+        let mut automaton_two = automaton.clone();
+        automaton_two.ranked_alphabet.insert("a".to_owned(), 1);
+        difference_automaton(&automaton, &automaton_two);
+    }
+}
@@ -1,17 +1,33 @@
+use crate::benchmark;
+use crate::chart_parser;
 use crate::computation;
 use crate::minimisation;
 use crate::parser;
 use crate::data_structures;
+use crate::sampling;
+use crate::string_automaton;
+use crate::tree_enumeration;
 use trees::tr;
 
-pub fn counter_example() {
+// Minimises the automaton and, if canonical is set, brings the result into its canonical form.
+fn minimise(
+    automaton: &data_structures::WeightedTreeAutomatonMatrix,
+    canonical: bool,
+) -> data_structures::WeightedTreeAutomatonMatrix {
+    if canonical {
+        return minimisation::minimize_automaton_canonical(automaton.clone());
+    }
+    return minimisation::minimize_automaton(automaton.clone());
+}
+
+pub fn counter_example(canonical: bool) {
     println!("Counter example: ");
     let struct_automaton = parser::read_bottom_up_grammar("automata/bottom_up/counter_tree");
     let automaton = data_structures::prod_vec_to_automaton(struct_automaton);
-    let minimised_automaton = minimisation::minimize_automaton(automaton.clone());
+    let minimised_automaton = minimise(&automaton, canonical);
     let struct_automaton_times_two = parser::read_bottom_up_grammar("automata/bottom_up/counter_tree_times_two");
     let automaton_times_two = data_structures::prod_vec_to_automaton(struct_automaton_times_two);
-    let minimised_automaton_times_two = minimisation::minimize_automaton(automaton_times_two.clone());
+    let minimised_automaton_times_two = minimise(&automaton_times_two, canonical);
     
     println!("\n\nA: Counter automaton:\n\n{}", automaton);
     println!("\n\nÃ: Counter automaton minimised :\n\n{}", minimised_automaton);
@@ -83,16 +99,16 @@ pub fn counter_example() {
 }
 
 
-pub fn computation_example() {
+pub fn computation_example(canonical: bool) {
     println!("Computation example:");
 
     let struct_automaton = parser::read_bottom_up_grammar("automata/bottom_up/computation_tree");
     let automaton = data_structures::prod_vec_to_automaton(struct_automaton);
-    let minimised_automaton = minimisation::minimize_automaton(automaton.clone());
+    let minimised_automaton = minimise(&automaton, canonical);
 
     let struct_automaton_times_two = parser::read_bottom_up_grammar("automata/bottom_up/computation_tree_times_two");
     let automaton_times_two = data_structures::prod_vec_to_automaton(struct_automaton_times_two);
-    let minimised_automaton_times_two = minimisation::minimize_automaton(automaton_times_two.clone());
+    let minimised_automaton_times_two = minimise(&automaton_times_two, canonical);
     println!("\n\nComputation Automaton:\n\n{}", automaton);
     println!("\n\nComputation Automaton minimised :\n\n{}", minimised_automaton);
     println!("\n\nComputation Automaton x2:\n\n{}", automaton_times_two);
@@ -177,16 +193,16 @@ pub fn computation_example() {
 
 
 
-pub fn simple_rgb_example() {
+pub fn simple_rgb_example(canonical: bool) {
     println!("Simple computation example with a minimised automaton: ");
 
     let struct_automaton = parser::read_bottom_up_grammar("automata/bottom_up/rgb_tree");
     let automaton = data_structures::prod_vec_to_automaton(struct_automaton);
-    let minimised_automaton = minimisation::minimize_automaton(automaton.clone());
+    let minimised_automaton = minimise(&automaton, canonical);
 
     let struct_automaton_times_two = parser::read_bottom_up_grammar("automata/bottom_up/rgb_tree_times_two");
     let automaton_times_two = data_structures::prod_vec_to_automaton(struct_automaton_times_two);
-    let minimised_automaton_times_two = minimisation::minimize_automaton(automaton_times_two.clone());
+    let minimised_automaton_times_two = minimise(&automaton_times_two, canonical);
    
     println!("\n\nrgb automaton:\n\n{}", automaton);
     println!("\n\nrgb automaton minimised :\n\n{}", minimised_automaton);
@@ -256,3 +272,101 @@ pub fn simple_rgb_example() {
     println!("||B̃||(t): (128064064) xrgbx 2       ≈ {:.2}", val_6_minimised_times_two);        
     println!();
 }
+
+pub fn enumerate_example(path: &str, max_size: usize, tolerance: f64, canonical: bool) {
+    let struct_automaton = parser::read_bottom_up_grammar(path);
+    let automaton = data_structures::prod_vec_to_automaton(struct_automaton);
+    let minimised_automaton = minimise(&automaton, canonical);
+    println!("Comparing {} ({} states) with its minimised version ({} states):", path, automaton.num_states, minimised_automaton.num_states);
+    tree_enumeration::print_comparison_table(&automaton, &minimised_automaton, max_size, tolerance);
+}
+
+// Reads either a bottom-up automaton or a top-down grammar with its lexicon, which is converted into a bottom-up
+// automaton with the given root state.
+pub fn read_automaton(
+    file: Option<&str>,
+    grammar: Option<&str>,
+    lexicon: Option<&str>,
+    root: &str,
+) -> data_structures::WeightedTreeAutomatonMatrix {
+    if let Some(path) = file {
+        return data_structures::prod_vec_to_automaton(parser::read_bottom_up_grammar(path));
+    }
+    if let (Some(path_grammar), Some(path_lexicon)) = (grammar, lexicon) {
+        let top_down = parser::read_top_down_automaton(path_grammar, path_lexicon);
+        return data_structures::prod_vec_to_automaton(data_structures::top_down_to_bottom_up(&top_down, root));
+    }
+    panic!("Please enter either an automaton file or a grammar together with its lexicon - see --help.");
+}
+
+pub fn sample_example(automaton: &data_structures::WeightedTreeAutomatonMatrix, n: usize, seed: u64, output: Option<&str>) {
+    let mut sampler = sampling::TreeSampler::new(automaton, seed);
+    let mut result = String::new();
+    for _ in 0..n {
+        result.push_str(&data_structures::tree_to_brackets(&sampler.sample()));
+        result.push('\n');
+    }
+    match output {
+        Some(path) => std::fs::write(path, result).unwrap(),
+        None => print!("{result}"),
+    }
+}
+
+pub fn benchmark_example(family: &str, max: Option<usize>, repetitions: usize, seed: u64, output: Option<&str>) {
+    let families: Vec<&str> = if family == "all" { benchmark::FAMILIES.to_vec() } else { vec![family] };
+    let mut result = format!("{}\n", benchmark::CSV_HEADER);
+    for family in families {
+        let max_parameter = max.unwrap_or_else(|| benchmark::default_max_parameter(family));
+        for benchmark_result in benchmark::benchmark_family(family, max_parameter, repetitions, seed) {
+            result.push_str(&benchmark_result.to_csv());
+            result.push('\n');
+        }
+    }
+    match output {
+        Some(path) => std::fs::write(path, result).unwrap(),
+        None => print!("{result}"),
+    }
+}
+
+pub fn string_automaton_example(path: &str, words_path: Option<&str>) {
+    let automaton = parser::read_string_automaton(path);
+    let minimised_automaton = string_automaton::minimise_string_automaton(&automaton);
+    println!("\n\nA: {path}:\n\n{automaton}");
+    println!("\n\nÃ: {path} minimised:\n\n{minimised_automaton}");
+    if let Some(words_path) = words_path {
+        println!("{:<40} {:>20} {:>20}", "Word", "||A||(w)", "||Ã||(w)");
+        for word in parser::read_words(words_path) {
+            println!(
+                "{:<40} {:>20.6} {:>20.6}",
+                word.join(" "),
+                automaton.weight(&word),
+                minimised_automaton.weight(&word)
+            );
+        }
+    }
+}
+
+pub fn yield_compare_example(
+    automaton: &data_structures::WeightedTreeAutomatonMatrix,
+    sentences_path: &str,
+    zero_threshold: f64,
+    canonical: bool,
+) {
+    let minimised_automaton = minimise(automaton, canonical);
+    println!(
+        "Comparing the sentence weights of the automaton ({} states) and its minimised version ({} states):",
+        automaton.num_states, minimised_automaton.num_states
+    );
+    let sentences = parser::read_words(sentences_path);
+    let (weights, max_difference) = chart_parser::compare_yields(
+        &data_structures::automaton_to_prod_vec(automaton),
+        &data_structures::automaton_to_prod_vec(&minimised_automaton),
+        &sentences,
+        zero_threshold,
+    );
+    println!("{:<60} {:>20} {:>20}", "Sentence", "||A||(w)", "||Ã||(w)");
+    for (sentence, (weight, minimised_weight)) in sentences.iter().zip(weights) {
+        println!("{:<60} {:>20.10e} {:>20.10e}", sentence.join(" "), weight, minimised_weight);
+    }
+    println!("Maximum relative difference: {max_difference:e}");
+}
@@ -0,0 +1,98 @@
+use crate::computation::compute_vector_for_tree;
+use crate::data_structures::{plug_into_context, WeightedTreeAutomatonMatrix};
+use crate::minimisation::minimize_automaton;
+use crate::step_i::step_i_forward_with_trees;
+use crate::step_ii::step_ii_backward_with_contexts;
+use nalgebra::DMatrix;
+use log::{debug, info, warn};
+use trees::Tree;
+
+type DynamicMatrix = DMatrix<f64>;
+
+// All singular values below eps are considered equal to 0 when used in numeric algorithms.
+const EPS: f64 = 0.00001;
+
+/*
+    The Hankel matrix H of a tree series is indexed by trees t and contexts c with H[t, c] = ||A||(c[t]).
+    Its rank is equal to the number of states of a minimal automaton for the series. Since H is infinite, we only
+    look at finite submatrices. By default we use the spanning trees of step I and the contexts of step II, which
+    span the forward and the backward space, so the rank of that submatrix already is the minimal dimension.
+*/
+
+// Builds the finite Hankel submatrix H[t, c] = ||A||(c[t]) for the given trees (rows) and contexts (columns).
+pub fn hankel_matrix(
+    automaton_matrix: &WeightedTreeAutomatonMatrix,
+    trees: &[Tree<String>],
+    contexts: &[Tree<String>],
+) -> DynamicMatrix {
+    // The trees and contexts don't depend on the representation, so top-down automata are evaluated bottom-up.
+    let automaton_matrix = &automaton_matrix.to_bottom_up();
+    let γ = automaton_matrix.transition_matrices.get("!").unwrap();
+    let mut h = DynamicMatrix::from_element(trees.len(), contexts.len(), 0.0);
+    for (row_num, tree) in trees.iter().enumerate() {
+        for (column_num, context) in contexts.iter().enumerate() {
+            let c_t = plug_into_context(context, tree);
+            h[(row_num, column_num)] = (compute_vector_for_tree(automaton_matrix, &c_t) * γ)[(0, 0)];
+        }
+    }
+    debug!("H: {h}");
+    return h;
+}
+
+// Returns the trees recorded by step_i_forward and the contexts recorded by step_ii_backward.
+pub fn default_trees_and_contexts(
+    automaton_matrix: &WeightedTreeAutomatonMatrix,
+) -> (Vec<Tree<String>>, Vec<Tree<String>>) {
+    let automaton_matrix = &automaton_matrix.to_bottom_up();
+    let (f, spanning_trees) = step_i_forward_with_trees(automaton_matrix);
+    let (_, contexts) = step_ii_backward_with_contexts(automaton_matrix, &f, &spanning_trees);
+    return (spanning_trees, contexts);
+}
+
+// Computes the rank of the Hankel submatrix given by the default trees and contexts.
+pub fn hankel_rank(automaton_matrix: &WeightedTreeAutomatonMatrix) -> usize {
+    let (trees, contexts) = default_trees_and_contexts(automaton_matrix);
+    let h = hankel_matrix(automaton_matrix, &trees, &contexts);
+    if h.nrows() == 0 || h.ncols() == 0 {
+        return 0;
+    }
+    return h.rank(EPS);
+}
+
+// Compares the rank of the Hankel matrix with the number of states of the minimised automaton.
+// Returns the pair (rank, num_states), both values should be equal.
+pub fn cross_check_minimal_dimension(automaton_matrix: &WeightedTreeAutomatonMatrix) -> (usize, usize) {
+    let rank = hankel_rank(automaton_matrix);
+    let num_states = minimize_automaton(automaton_matrix.clone()).num_states;
+    if rank == num_states {
+        info!("Rank of the Hankel matrix and number of states of the minimal automaton are both {rank}");
+    } else {
+        warn!("Rank of the Hankel matrix is {rank}, but the minimal automaton has {num_states} states");
+    }
+    return (rank, num_states);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{data_structures, parser};
+
+    #[test]
+    fn hankel_counter_example() {
+        let automaton = data_structures::prod_vec_to_automaton(
+            parser::read_bottom_up_grammar("automata/bottom_up/counter_tree"),
+        );
+        let (rank, num_states) = cross_check_minimal_dimension(&automaton);
+        assert_eq!(rank, 2);
+        assert_eq!(rank, num_states);
+
+        // H[a, s(□, b)] = ||A||(s(a, b)) = 0 and H[b, s(□, b)] = ||A||(s(b, b)) = -2.
+        let trees = vec![Tree::new("a".to_owned()), Tree::new("b".to_owned())];
+        let mut context = Tree::new("s".to_owned());
+        context.push_back(Tree::new(data_structures::CONTEXT_HOLE.to_owned()));
+        context.push_back(Tree::new("b".to_owned()));
+        let h = hankel_matrix(&automaton, &trees, &[context]);
+        assert!((h[(0, 0)] - 0.0).abs() < EPS);
+        assert!((h[(1, 0)] + 2.0).abs() < EPS);
+    }
+}
@@ -0,0 +1,291 @@
+use crate::data_structures::WeightedTreeAutomatonMatrix;
+use nalgebra::DMatrix;
+use log::{debug, warn};
+use std::collections::BTreeMap;
+use trees::{Node, Tree};
+
+type DynamicMatrix = DMatrix<f64>;
+
+/*
+    Inside and outside weights of the nodes of a packed forest, that is a hypergraph in which every node v has a set
+    of incoming hyperedges σ(v_1, ..., v_k). The node v stands for all trees σ(t_1, ..., t_k) where t_i is a tree of
+    v_i, so a forest can represent exponentially many trees, for example all parses of a sentence.
+    - The inside weight α(v) is the row vector Σ_t μ(t) over all trees t of v, computed bottom-up:
+      α(v) = Σ_{σ(v_1, ..., v_k) → v} (α(v_1) ⊗ ... ⊗ α(v_k)) μ_σ.
+    - The outside weight β(v) is the column vector such that α(v) β(v) is the weight of all trees of the root that
+      use v, computed top-down: β(root) = γ and every hyperedge σ(v_1, ..., v_k) → v adds
+      (α(v_1) ⊗ ... ⊗ I ⊗ ... ⊗ α(v_k)) μ_σ β(v) to β(v_i), with the identity matrix at position i.
+    A single tree is the forest in which every node has exactly one incoming hyperedge.
+*/
+
+#[derive(Debug, Clone)]
+pub struct Hyperedge {
+    pub symbol: String,
+    pub children: Vec<usize>,
+}
+
+// The children of a hyperedge always have smaller indices than the node the hyperedge leads to, so the nodes are
+// sorted bottom-up. The fields are private, so that every forest goes through Forest::new, which checks this.
+#[derive(Debug, Clone)]
+pub struct Forest {
+    // incoming_edges[v] contains all hyperedges that lead to node v.
+    incoming_edges: Vec<Vec<Hyperedge>>,
+    root: usize,
+}
+
+impl Forest {
+    // Panics if a hyperedge has a child whose index isn't smaller than the index of its node, or if the root isn't
+    // a node of the forest.
+    pub fn new(incoming_edges: Vec<Vec<Hyperedge>>, root: usize) -> Forest {
+        if root >= incoming_edges.len() {
+            panic!("The root {root} isn't one of the {} nodes of the forest.", incoming_edges.len());
+        }
+        for (v, edges) in incoming_edges.iter().enumerate() {
+            for edge in edges {
+                if let Some(child) = edge.children.iter().find(|child| **child >= v) {
+                    panic!(
+                        "The hyperedge {} into node {v} has the child {child}, but children need smaller indices than their node.",
+                        edge.symbol
+                    );
+                }
+            }
+        }
+        return Forest { incoming_edges, root };
+    }
+
+    // The nodes of the forest are the nodes of the tree in post-order, so the root is the last node.
+    pub fn from_tree(tree: &Tree<String>) -> Forest {
+        fn add_rec(node: &Node<String>, incoming_edges: &mut Vec<Vec<Hyperedge>>) -> usize {
+            let children = node.iter().map(|child| add_rec(child, incoming_edges)).collect();
+            incoming_edges.push(vec![Hyperedge { symbol: node.data().clone(), children }]);
+            return incoming_edges.len() - 1;
+        }
+        let mut incoming_edges = Vec::new();
+        let root = add_rec(tree.root(), &mut incoming_edges);
+        return Forest { incoming_edges, root };
+    }
+
+    pub fn num_nodes(&self) -> usize {
+        return self.incoming_edges.len();
+    }
+
+    pub fn incoming_edges(&self) -> &[Vec<Hyperedge>] {
+        return &self.incoming_edges;
+    }
+
+    pub fn root(&self) -> usize {
+        return self.root;
+    }
+}
+
+// (v_1 ⊗ ... ⊗ v_k) where the vector at identity_position (if any) is replaced by the identity matrix.
+fn kronecker_of_children(
+    inside: &[DynamicMatrix],
+    children: &[usize],
+    identity_position: Option<usize>,
+    n: usize,
+) -> DynamicMatrix {
+    let identity = DynamicMatrix::identity(n, n);
+    let mut result = DynamicMatrix::from_element(1, 1, 1.0);
+    for (position, child) in children.iter().enumerate() {
+        if Some(position) == identity_position {
+            result = result.kronecker(&identity);
+        } else {
+            result = result.kronecker(&inside[*child]);
+        }
+    }
+    return result;
+}
+
+// Computes α(v) for all nodes v of the forest.
+pub fn inside_weights(automaton_matrix: &WeightedTreeAutomatonMatrix, forest: &Forest) -> Vec<DynamicMatrix> {
+    let automaton_matrix = automaton_matrix.to_bottom_up();
+    let n = automaton_matrix.num_states;
+    let mut inside: Vec<DynamicMatrix> = Vec::new();
+    for edges in forest.incoming_edges.iter() {
+        let mut α = DynamicMatrix::from_element(1, n, 0.0);
+        for edge in edges {
+            let μ_σ = automaton_matrix.transition_matrices.get(&edge.symbol).unwrap();
+            α += kronecker_of_children(&inside, &edge.children, None, n) * μ_σ;
+        }
+        inside.push(α);
+    }
+    return inside;
+}
+
+// Computes β(v) for all nodes v of the forest, inside has to be the result of inside_weights.
+pub fn outside_weights(
+    automaton_matrix: &WeightedTreeAutomatonMatrix,
+    forest: &Forest,
+    inside: &[DynamicMatrix],
+) -> Vec<DynamicMatrix> {
+    let automaton_matrix = automaton_matrix.to_bottom_up();
+    let n = automaton_matrix.num_states;
+    let mut outside = vec![DynamicMatrix::from_element(n, 1, 0.0); forest.num_nodes()];
+    outside[forest.root] = automaton_matrix.transition_matrices.get("!").unwrap().clone();
+    // The nodes are sorted bottom-up, so going through them backwards every node is finished before its children.
+    for v in (0..forest.num_nodes()).rev() {
+        for edge in forest.incoming_edges[v].iter() {
+            let μ_σ_β = automaton_matrix.transition_matrices.get(&edge.symbol).unwrap() * &outside[v];
+            for (position, child) in edge.children.iter().enumerate() {
+                let contribution = kronecker_of_children(inside, &edge.children, Some(position), n) * &μ_σ_β;
+                outside[*child] += contribution;
+            }
+        }
+    }
+    return outside;
+}
+
+// The sum of the weights of all trees in the forest.
+pub fn forest_weight(automaton_matrix: &WeightedTreeAutomatonMatrix, forest: &Forest) -> f64 {
+    let inside = inside_weights(automaton_matrix, forest);
+    let γ = automaton_matrix.to_bottom_up().transition_matrices.get("!").unwrap().clone();
+    return (&inside[forest.root] * γ)[(0, 0)];
+}
+
+// For every node v the row vector of the posterior state marginals α(v)_q β(v)_q / Z, where Z is the weight of the
+// forest. For a probabilistic automaton this is the probability that the run is in state q at node v.
+// A forest of weight 0 has no posterior, in that case all marginals are 0.
+pub fn state_marginals(automaton_matrix: &WeightedTreeAutomatonMatrix, forest: &Forest) -> Vec<DynamicMatrix> {
+    let inside = inside_weights(automaton_matrix, forest);
+    let outside = outside_weights(automaton_matrix, forest, &inside);
+    let z = (&inside[forest.root] * &outside[forest.root])[(0, 0)];
+    if z == 0.0 {
+        warn!("The forest has weight 0, so all state marginals are 0");
+        return inside.iter().map(|α| DynamicMatrix::from_element(1, α.ncols(), 0.0)).collect();
+    }
+    return inside
+        .iter()
+        .zip(outside.iter())
+        .map(|(α, β)| α.component_mul(&β.transpose()) / z)
+        .collect();
+}
+
+// The expected number of uses of every transition in the runs on the trees of the forest, in the shape of the
+// transition matrices: counts_σ[(q_1, ..., q_k), q] = Σ_{edges σ(v_1, ..., v_k) → v}
+// α(v_1)_q_1 ... α(v_k)_q_k μ_σ[(q_1, ..., q_k), q] β(v)_q / Z. The entry "!" contains α(root)_q γ_q / Z.
+// A forest of weight 0 has no runs to count, in that case all counts are 0.
+pub fn expected_counts(
+    automaton_matrix: &WeightedTreeAutomatonMatrix,
+    forest: &Forest,
+) -> BTreeMap<String, DynamicMatrix> {
+    let automaton_matrix = automaton_matrix.to_bottom_up();
+    let n = automaton_matrix.num_states;
+    let inside = inside_weights(&automaton_matrix, forest);
+    let outside = outside_weights(&automaton_matrix, forest, &inside);
+    let γ = automaton_matrix.transition_matrices.get("!").unwrap();
+    let z = (&inside[forest.root] * γ)[(0, 0)];
+    debug!("Weight of the forest: {z}");
+
+    let mut counts: BTreeMap<String, DynamicMatrix> = automaton_matrix
+        .transition_matrices
+        .iter()
+        .map(|(σ, μ_σ)| (σ.clone(), DynamicMatrix::from_element(μ_σ.nrows(), μ_σ.ncols(), 0.0)))
+        .collect();
+    if z == 0.0 {
+        warn!("The forest has weight 0, so all expected counts are 0");
+        return counts;
+    }
+    for (v, edges) in forest.incoming_edges.iter().enumerate() {
+        for edge in edges {
+            let μ_σ = automaton_matrix.transition_matrices.get(&edge.symbol).unwrap();
+            let children = kronecker_of_children(&inside, &edge.children, None, n);
+            let count = counts.get_mut(&edge.symbol).unwrap();
+            for row in 0..μ_σ.nrows() {
+                for q in 0..n {
+                    count[(row, q)] += children[(0, row)] * μ_σ[(row, q)] * outside[v][(q, 0)] / z;
+                }
+            }
+        }
+    }
+    let final_count = inside[forest.root].transpose().component_mul(γ) / z;
+    counts.insert("!".to_owned(), final_count);
+    return counts;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::computation::compute_val_for_tree;
+    use crate::{data_structures, parser};
+
+    fn leaf(label: &str) -> Tree<String> {
+        return Tree::new(label.to_owned());
+    }
+
+    fn node(label: &str, children: Vec<Tree<String>>) -> Tree<String> {
+        let mut tree = Tree::new(label.to_owned());
+        for child in children {
+            tree.push_back(child);
+        }
+        return tree;
+    }
+
+    #[test]
+    fn inside_outside_counter_example() {
+        let automaton = data_structures::prod_vec_to_automaton(
+            parser::read_bottom_up_grammar("automata/bottom_up/counter_tree"),
+        );
+        let tree = node("s", vec![node("s", vec![leaf("a"), leaf("a")]), leaf("b")]);
+        let forest = Forest::from_tree(&tree);
+        let inside = inside_weights(&automaton, &forest);
+        let outside = outside_weights(&automaton, &forest, &inside);
+        let val = compute_val_for_tree(automaton.clone(), tree.clone());
+        assert_eq!(val, 1.0);
+        // α(v) β(v) is the weight of the tree at every node.
+        for v in 0..forest.num_nodes() {
+            assert!(((&inside[v] * &outside[v])[(0, 0)] - val).abs() < 1e-12);
+        }
+
+        // A forest with the root s(x, b), where x is either s(a, a) or b, contains two trees.
+        let edge = |symbol: &str, children: Vec<usize>| Hyperedge { symbol: symbol.to_owned(), children };
+        let packed = Forest::new(
+            vec![
+                vec![edge("a", vec![])],
+                vec![edge("b", vec![])],
+                vec![edge("s", vec![0, 0]), edge("b", vec![])],
+                vec![edge("s", vec![2, 1])],
+            ],
+            3,
+        );
+        let other_tree = node("s", vec![leaf("b"), leaf("b")]);
+        let expected = val + compute_val_for_tree(automaton.clone(), other_tree);
+        assert!((forest_weight(&automaton, &packed) - expected).abs() < 1e-12);
+    }
+
+    #[test]
+    fn expected_counts_pcfg() {
+        let grammar = parser::read_top_down_automaton(
+            "automata/top_down/simple_pcfg.grammar",
+            "automata/top_down/simple_pcfg.lexicon",
+        );
+        let automaton = data_structures::prod_vec_to_automaton(data_structures::top_down_to_bottom_up(&grammar, "ROOT_0"));
+        let np = node("NP/2", vec![leaf("the"), leaf("dog")]);
+        let pp = node("PP", vec![leaf("with"), node("NP/1", vec![leaf("telescope")])]);
+        let vp = node("VP", vec![node("VP", vec![leaf("saw"), np.clone()]), pp]);
+        let tree = node("ROOT", vec![node("S", vec![np, vp])]);
+        let counts = expected_counts(&automaton, &Forest::from_tree(&tree));
+        // Every state belongs to exactly one symbol, so the expected counts are the numbers of occurrences.
+        assert!((counts.get("VP").unwrap().sum() - 2.0).abs() < 1e-9);
+        assert!((counts.get("the").unwrap().sum() - 2.0).abs() < 1e-9);
+        assert!((counts.get("!").unwrap().sum() - 1.0).abs() < 1e-9);
+        for marginal in state_marginals(&automaton, &Forest::from_tree(&tree)) {
+            assert!((marginal.sum() - 1.0).abs() < 1e-9);
+        }
+
+        // saw is only a verb, so NP/1(saw) and the whole tree have weight 0 and neither counts nor marginals.
+        let zero_np = node("NP/1", vec![leaf("saw")]);
+        let zero_vp = node("VP", vec![leaf("saw"), node("NP/1", vec![leaf("telescope")])]);
+        let zero_tree = node("ROOT", vec![node("S", vec![zero_np, zero_vp])]);
+        let zero_forest = Forest::from_tree(&zero_tree);
+        assert!(expected_counts(&automaton, &zero_forest).values().all(|count| count.iter().all(|c| *c == 0.0)));
+        assert!(state_marginals(&automaton, &zero_forest).iter().all(|marginal| marginal.iter().all(|m| *m == 0.0)));
+    }
+
+    #[test]
+    #[should_panic(expected = "children need smaller indices")]
+    fn forest_with_cycle() {
+        let edge = Hyperedge { symbol: "s".to_owned(), children: vec![1, 0] };
+        Forest::new(vec![vec![edge], vec![]], 1);
+    }
+}
@@ -1,16 +1,33 @@
-#![allow(confusable_idents)]
+#![allow(confusable_idents, clippy::needless_return)]
 
+pub mod active_learning;
+pub mod benchmark;
+pub mod canonical_form;
+pub mod chart_parser;
 pub mod computation;
 pub mod data_structures;
+pub mod em_training;
+pub mod equivalence;
 pub mod examples;
+pub mod hankel;
+pub mod inside_outside;
 pub mod minimisation;
+pub mod parallel;
 pub mod parser;
+pub mod partition_function;
+pub mod random;
+pub mod random_automaton;
+pub mod sampling;
+pub mod semiring;
+pub mod spectral;
 pub mod step_i;
 pub mod step_ii;
 pub mod step_iii;
+pub mod string_automaton;
+pub mod tree_enumeration;
 
 // Used for command line arguments.
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use log::debug;
 use crate::examples::*;
 
@@ -20,24 +37,146 @@ use crate::examples::*;
 struct Args {
     /// Automaton: {counter, computation, rgb, all}
     #[arg(short, long)]
-    automaton: String,
+    automaton: Option<String>,
+
+    /// Number of threads used in step I and step II, by default all cores are used
+    #[arg(short = 'j', long, global = true)]
+    threads: Option<usize>,
+
+    /// Bring the minimised tree automata into their canonical form, so that equivalent automata give the same matrices
+    #[arg(long, global = true)]
+    canonical: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Lists all trees up to the given size with their weights in the automaton and in its minimised version.
+    Enumerate {
+        /// Path to a bottom-up automaton, for example automata/bottom_up/counter_tree
+        #[arg(short, long)]
+        file: String,
+        /// Maximal number of nodes of the listed trees
+        #[arg(short, long)]
+        max_size: usize,
+        /// Trees whose weights differ by more than this value (relative to the weight if it is larger than 1) are flagged
+        #[arg(short, long, default_value_t = 0.00001)]
+        tolerance: f64,
+    },
+    /// Samples trees from a probabilistic automaton and writes them in bracket notation.
+    Sample {
+        /// Number of trees
+        #[arg(short = 'n', long)]
+        num_samples: usize,
+        /// Path to a bottom-up automaton
+        #[arg(short, long)]
+        file: Option<String>,
+        /// Path to a top-down grammar in the format of the Berkeley parser (used together with --lexicon)
+        #[arg(short, long)]
+        grammar: Option<String>,
+        /// Path to the lexicon of the top-down grammar
+        #[arg(short, long)]
+        lexicon: Option<String>,
+        /// Root state of the top-down grammar
+        #[arg(short, long, default_value = "ROOT_0")]
+        root: String,
+        /// Seed for the random number generator
+        #[arg(short, long, default_value_t = 0)]
+        seed: u64,
+        /// File to write the trees to, by default they are printed
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    /// Minimises a weighted string automaton and compares the weights of words under both automata.
+    Wfa {
+        /// Path to a string automaton, for example automata/string/count_a
+        #[arg(short, long)]
+        file: String,
+        /// Path to a file with one word per line, the letters are separated by whitespace
+        #[arg(short, long)]
+        words: Option<String>,
+    },
+    /// Parses the sentences of a file with an automaton and its minimised version and compares the sentence weights.
+    YieldCompare {
+        /// Path to a bottom-up automaton
+        #[arg(short, long)]
+        file: Option<String>,
+        /// Path to a top-down grammar in the format of the Berkeley parser (used together with --lexicon)
+        #[arg(short, long)]
+        grammar: Option<String>,
+        /// Path to the lexicon of the top-down grammar
+        #[arg(short, long)]
+        lexicon: Option<String>,
+        /// Root state of the top-down grammar
+        #[arg(short, long, default_value = "ROOT_0")]
+        root: String,
+        /// Path to a file with one sentence per line, the words are separated by whitespace
+        #[arg(short, long)]
+        sentences: String,
+        /// Sentences whose weight under the original automaton is at most this value in absolute value are left out of
+        /// the maximum relative difference
+        #[arg(short, long, default_value_t = 0.0)]
+        zero_threshold: f64,
+    },
+    /// Times the three steps of the minimisation on families of random automata and writes the results as CSV.
+    Benchmark {
+        /// Family of automata: {states, rank, alphabet, all}
+        #[arg(short, long, default_value = "all")]
+        family: String,
+        /// Largest number of states, rank or alphabet size in the family
+        #[arg(short, long)]
+        max: Option<usize>,
+        /// Number of runs per automaton, the fastest one is reported
+        #[arg(short, long, default_value_t = 3)]
+        repetitions: usize,
+        /// Seed for the random automata
+        #[arg(short, long, default_value_t = 0)]
+        seed: u64,
+        /// File to write the CSV to, by default it is printed
+        #[arg(short, long)]
+        output: Option<String>,
+    },
 }
 
 fn main() {
     env_logger::init();
     let args = Args::parse();
     debug!("Debug mode enabled.");
-    println!("Execution {}", args.automaton);
-    if args.automaton == "counter" {
-        counter_example();
-    } else if args.automaton == "computation" {
-        computation_example();
-    } else if args.automaton == "rgb" {
-        simple_rgb_example();
-    } else if args.automaton == "all" {
-        counter_example();
-        computation_example();
-        simple_rgb_example();
+    if let Some(threads) = args.threads {
+        parallel::set_num_threads(threads);
+    }
+    if let Some(command) = args.command {
+        match command {
+            Command::Enumerate { file, max_size, tolerance } => enumerate_example(&file, max_size, tolerance, args.canonical),
+            Command::Sample { num_samples, file, grammar, lexicon, root, seed, output } => {
+                let automaton = read_automaton(file.as_deref(), grammar.as_deref(), lexicon.as_deref(), &root);
+                sample_example(&automaton, num_samples, seed, output.as_deref());
+            }
+            Command::Wfa { file, words } => string_automaton_example(&file, words.as_deref()),
+            Command::YieldCompare { file, grammar, lexicon, root, sentences, zero_threshold } => {
+                let automaton = read_automaton(file.as_deref(), grammar.as_deref(), lexicon.as_deref(), &root);
+                yield_compare_example(&automaton, &sentences, zero_threshold, args.canonical);
+            }
+            Command::Benchmark { family, max, repetitions, seed, output } => {
+                benchmark_example(&family, max, repetitions, seed, output.as_deref())
+            }
+        }
+        return;
+    }
+    let automaton = args.automaton.unwrap_or_default();
+    println!("Execution {}", automaton);
+    if automaton == "counter" {
+        counter_example(args.canonical);
+    } else if automaton == "computation" {
+        computation_example(args.canonical);
+    } else if automaton == "rgb" {
+        simple_rgb_example(args.canonical);
+    } else if automaton == "all" {
+        counter_example(args.canonical);
+        computation_example(args.canonical);
+        simple_rgb_example(args.canonical);
     } else {
         println!("Please enter a valid automaton - see --help.")
     }
@@ -1,8 +1,10 @@
-use crate::data_structures::WeightedTreeAutomatonMatrix;
+use crate::data_structures::{plug_into_context, GrammarType, WeightedTreeAutomatonMatrix, CONTEXT_HOLE};
 use nalgebra::DMatrix;
 use std::collections::VecDeque;
 use log::{debug, info};
-use crate::step_i::step_i_forward;
+use trees::Tree;
+use crate::canonical_form::canonicalise;
+use crate::step_i::step_i_forward_with_trees;
 use crate::step_ii::step_ii_backward;
 use crate::step_iii::step_iii_solve;
 
@@ -15,54 +17,72 @@ const EPS: f64 = 0.00001;
 pub fn minimize_automaton(
     automaton_matrix: WeightedTreeAutomatonMatrix,
 ) -> WeightedTreeAutomatonMatrix {
+    // The minimisation works on bottom-up automata, top-down automata are transposed before and afterwards.
+    if let GrammarType::TopDown = automaton_matrix.grammar_type {
+        return minimize_automaton(automaton_matrix.to_bottom_up()).to_top_down();
+    }
     info!("Minimizing:");
-    let f = step_i_forward(&automaton_matrix);
+    let (f, spanning_trees) = step_i_forward_with_trees(&automaton_matrix);
     info!("F: {f}");
-    let b = step_ii_backward(&automaton_matrix, &f);
+    let b = step_ii_backward(&automaton_matrix, &f, &spanning_trees);
     info!("B: {b}");
     let new_a = step_iii_solve(&automaton_matrix, &f, &b);
     return new_a;
 }
 
+// Minimises the automaton and brings the result into its canonical form (see canonical_form.rs), so that equivalent
+// inputs give the same matrices no matter in which order their states are numbered.
+pub fn minimize_automaton_canonical(
+    automaton_matrix: WeightedTreeAutomatonMatrix,
+) -> WeightedTreeAutomatonMatrix {
+    if let GrammarType::TopDown = automaton_matrix.grammar_type {
+        return minimize_automaton_canonical(automaton_matrix.to_bottom_up()).to_top_down();
+    }
+    return canonicalise(&minimize_automaton(automaton_matrix));
+}
+
 
 
+// Tzeng's algorithm: computes a basis of the smallest vector space that contains the given column and is closed
+// under multiplication with every matrix in m. The basis vectors are appended as columns to b, which has to have
+// num_states rows. This is a worklist algorithm: every vector that turns out to be independent of b is added to b
+// and the vectors M v for all M ∈ m are queued, since they might leave the span of b. Dependent vectors are dropped
+// together with their successors, because those are in the span of the successors of the vectors of b.
+// m_contexts contains the context that belongs to each element of M. The returned vector contains the context
+// of every vector that was added to b, starting with the empty context of the given column.
 pub fn tzeng(
-    column: &mut DynamicMatrix,
+    column: &DynamicMatrix,
     b: &mut DynamicMatrix,
-    m: &Vec<DynamicMatrix>,
-) { 
-    let mut column_queue = VecDeque::new();   
-    //let mut temp_b = b.clone();
-    column_queue.push_back(column.clone());
-    let mut filled_columns = 0;
-    let max_rank = b.column_iter().count();
-    while column_queue.len() != 0 {
-        if b.rank(EPS) == max_rank {
-            return;
+    m: &[DynamicMatrix],
+    m_contexts: &[Tree<String>],
+) -> Vec<Tree<String>> {
+    let mut column_queue = VecDeque::new();
+    let mut contexts = Vec::new();
+    column_queue.push_back((column.clone(), Tree::new(CONTEXT_HOLE.to_owned())));
+    let max_rank = b.nrows();
+    let mut old_rank = if b.ncols() == 0 { 0 } else { b.rank(EPS) };
+    while let Some((temp_column, temp_context)) = column_queue.pop_front() {
+        if old_rank == max_rank {
+            break;
         }
-
         debug!("column_queue.len() {}", column_queue.len());
-        let temp_column = column_queue.pop_front().unwrap();
-
-        let mut new_b = b.clone();
-        for i in 0..column.len() {
-            new_b[(filled_columns, i)] = temp_column[(i, 0)];
-        }
-        debug!("new_b {new_b}");
+        let num_columns = b.ncols();
+        let mut new_b = b.clone().insert_column(num_columns, 0.0);
+        new_b.set_column(num_columns, &temp_column.column(0));
         let new_rank = new_b.rank(EPS);
-        let old_rank = b.rank(EPS);        
-
         if new_rank > old_rank {
-            debug!("independant");
+            debug!("independent: {temp_column}");
             *b = new_b;
-            for c in m {
-                let new_column = c * &column.clone();
-                column_queue.push_back(new_column);
+            old_rank = new_rank;
+            // The vector M v belongs to the context c[c_M], where c is the context of v and c_M the one of M.
+            for (c, c_context) in m.iter().zip(m_contexts) {
+                let new_column = c * &temp_column;
+                column_queue.push_back((new_column, plug_into_context(&temp_context, c_context)));
             }
-            filled_columns += 1;
+            contexts.push(temp_context);
         }
     }
-    return;
+    return contexts;
 }
 
 
@@ -82,21 +102,201 @@ pub fn next_tuple(mut x: Vec<usize>, n: usize, force_n: bool) -> Option<Vec<usiz
         if j == 0 {
             return None;
         }
-        j = j - 1;
+        j -= 1;
     }
 
-    for l in j..k {
-        if x[l] == n {
-            x[l] = 1;
+    for x_l in x.iter_mut().skip(j) {
+        if *x_l == n {
+            *x_l = 1;
         } else {
-            x[l] = x[l] + 1;
+            *x_l += 1;
         }
     }
     // Here we check whether x contains an instance of n.
-    if x.contains(&n) || force_n == false {
+    if x.contains(&n) || !force_n {
         return Some(x);
     } else {
         x = next_tuple(x.clone(), n, force_n).unwrap();
         return Some(x);
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::computation::{compute_val_for_tree, compute_vector_for_tree};
+    use crate::random::Random;
+    use crate::step_ii::step_ii_backward_with_contexts;
+    use std::collections::BTreeMap;
+    use crate::random_automaton::{random_automaton_matrix, random_tree, RandomAutomatonConfig, WeightDistribution};
+
+    // Number of random trees on which the weights of an automaton and its minimised version are compared.
+    const NUM_TREES: usize = 30;
+    const TREE_HEIGHT: usize = 4;
+
+    fn assert_same_weights(
+        a: &WeightedTreeAutomatonMatrix,
+        b: &WeightedTreeAutomatonMatrix,
+        random: &mut Random,
+        seed: u64,
+    ) {
+        for _ in 0..NUM_TREES {
+            let tree = random_tree(random, &a.ranked_alphabet, TREE_HEIGHT);
+            let val_a = compute_val_for_tree(a.clone(), tree.clone());
+            let val_b = compute_val_for_tree(b.clone(), tree.clone());
+            assert!(
+                (val_a - val_b).abs() <= 0.001 * val_a.abs().max(1.0),
+                "Seed {seed}: ||A||({tree}) = {val_a}, but the minimised automaton gives {val_b}"
+            );
+        }
+    }
+
+    // The random automata of the property tests: the sizes run through 1 <= minimal_states <= num_states <= 5,
+    // every second automaton has normally distributed weights and the last ones also have a symbol of rank 3.
+    fn random_configs() -> Vec<RandomAutomatonConfig> {
+        let mut configs = Vec::new();
+        for seed in 0..20 {
+            let num_states = 1 + (seed as usize % 5);
+            let minimal_states = 1 + (seed as usize / 5) % num_states;
+            let mut config = RandomAutomatonConfig::new(num_states, minimal_states, seed);
+            if seed % 2 == 1 {
+                config.weight_distribution = WeightDistribution::Normal { mean: 0.0, standard_deviation: 1.0 };
+            }
+            configs.push(config);
+        }
+        for seed in 100..105 {
+            let mut config = RandomAutomatonConfig::new(4, 2, seed);
+            config.ranked_alphabet.insert("t".to_owned(), 3);
+            config.density = 0.7;
+            configs.push(config);
+        }
+        return configs;
+    }
+
+    #[test]
+    fn minimisation_preserves_weights() {
+        for config in random_configs() {
+            let seed = config.seed;
+            let automaton = random_automaton_matrix(&config);
+            let minimised = minimize_automaton(automaton.clone());
+            assert!(minimised.num_states <= automaton.num_states, "Seed {seed}: the number of states increased");
+            assert_same_weights(&automaton, &minimised, &mut Random::new(seed), seed);
+        }
+    }
+
+    // An automaton with the states 0, ..., n - 1 where a leads to state 0 and σ(q_i, a, ..., a) to state i + 1.
+    // Only state n - 1 has a final weight, so ||A||(t) = 1 if t is the comb σ(σ(...σ(a, a, ...)...), a, ...) of
+    // height n - 1 and 0 otherwise. The backward space is spanned by γ, μ(σ(□, a, ...)) γ, ..., so Tzeng's
+    // algorithm needs n - 1 steps and the automaton is minimal.
+    fn chain_automaton(n: usize, rk_σ: usize) -> WeightedTreeAutomatonMatrix {
+        let mut ranked_alphabet = BTreeMap::new();
+        ranked_alphabet.insert("a".to_owned(), 0);
+        ranked_alphabet.insert("σ".to_owned(), rk_σ);
+        ranked_alphabet.insert("!".to_owned(), n);
+        let mut transition_matrices = BTreeMap::new();
+        let mut μ_a = DynamicMatrix::zeros(1, n);
+        μ_a[(0, 0)] = 1.0;
+        transition_matrices.insert("a".to_owned(), μ_a);
+        let mut μ_σ = DynamicMatrix::zeros(n.pow(rk_σ as u32), n);
+        for i in 0..(n - 1) {
+            // The other children are in state 0, so the row of (q_i, q_0, ..., q_0) is i * n^(rk_σ - 1).
+            μ_σ[(i * n.pow(rk_σ as u32 - 1), i + 1)] = 1.0;
+        }
+        transition_matrices.insert("σ".to_owned(), μ_σ);
+        let mut γ = DynamicMatrix::zeros(n, 1);
+        γ[(n - 1, 0)] = 1.0;
+        transition_matrices.insert("!".to_owned(), γ);
+        return WeightedTreeAutomatonMatrix {
+            num_states: n,
+            grammar_type: GrammarType::BottomUp,
+            ranked_alphabet,
+            transition_matrices,
+        };
+    }
+
+    #[test]
+    fn backward_space_needs_several_steps() {
+        for rk_σ in 1..4 {
+            for n in 2..6 {
+                let automaton = chain_automaton(n, rk_σ);
+                let (f, spanning_trees) = step_i_forward_with_trees(&automaton);
+                let (b, contexts) = step_ii_backward_with_contexts(&automaton, &f, &spanning_trees);
+                assert_eq!(b.ncols(), n, "rank {rk_σ}, {n} states: B is too small");
+                // Every recorded context belongs to the column of B in the same position.
+                for (column_num, context) in contexts.iter().enumerate() {
+                    for tree in spanning_trees.iter() {
+                        let val = compute_val_for_tree(automaton.clone(), plug_into_context(context, tree));
+                        let expected = (compute_vector_for_tree(&automaton, tree) * b.column(column_num))[(0, 0)];
+                        assert!((val - expected).abs() < EPS);
+                    }
+                }
+
+                let minimised = minimize_automaton(automaton.clone());
+                assert_eq!(minimised.num_states, n);
+                assert_same_weights(&automaton, &minimised, &mut Random::new(n as u64), n as u64);
+                // The only tree with weight 1 is the comb of height n - 1.
+                let mut comb = Tree::new("a".to_owned());
+                for _ in 0..(n - 1) {
+                    let mut new_comb = Tree::new("σ".to_owned());
+                    new_comb.push_back(comb);
+                    for _ in 1..rk_σ {
+                        new_comb.push_back(Tree::new("a".to_owned()));
+                    }
+                    comb = new_comb;
+                }
+                assert!((compute_val_for_tree(minimised.clone(), comb) - 1.0).abs() < EPS);
+            }
+        }
+    }
+
+    #[test]
+    fn minimisation_is_minimal() {
+        for config in random_configs() {
+            let seed = config.seed;
+            let minimised = minimize_automaton(random_automaton_matrix(&config));
+            assert_eq!(minimised.num_states, config.minimal_states, "Seed {seed}: not the planted minimal size");
+
+            let minimised_twice = minimize_automaton(minimised.clone());
+            assert_eq!(minimised_twice.num_states, minimised.num_states, "Seed {seed}: not idempotent");
+            assert_same_weights(&minimised, &minimised_twice, &mut Random::new(seed), seed);
+        }
+    }
+
+    #[test]
+    fn canonical_minimisation_ignores_state_order() {
+        let struct_automaton = crate::parser::read_bottom_up_grammar("automata/bottom_up/counter_tree");
+        // The same automaton with the states q1 and q3 swapped, which reverses their order in the matrices.
+        let mut swapped_struct = struct_automaton.clone();
+        let swap = |state: &String| match state.as_str() {
+            "q1" => "q3".to_owned(),
+            "q3" => "q1".to_owned(),
+            _ => state.clone(),
+        };
+        for prod_vec in swapped_struct.transition_structs.values_mut() {
+            for prod in prod_vec.iter_mut() {
+                prod.left_states = prod.left_states.iter().map(swap).collect();
+                prod.right_states = prod.right_states.iter().map(swap).collect();
+            }
+        }
+        let automaton = crate::data_structures::prod_vec_to_automaton(struct_automaton);
+        let swapped = crate::data_structures::prod_vec_to_automaton(swapped_struct);
+        assert_ne!(automaton.transition_matrices, swapped.transition_matrices);
+
+        let minimised = minimize_automaton_canonical(automaton);
+        let minimised_swapped = minimize_automaton_canonical(swapped);
+        assert_eq!(minimised.num_states, minimised_swapped.num_states);
+        for (symbol, μ_σ) in minimised.transition_matrices.iter() {
+            let other = minimised_swapped.transition_matrices.get(symbol).unwrap();
+            assert!((μ_σ - other).amax() < EPS, "{symbol}: {μ_σ} != {other}");
+        }
+    }
+
+    #[test]
+    fn minimise_zero_series() {
+        let automaton = random_automaton_matrix(&RandomAutomatonConfig::new(3, 0, 1));
+        for minimised in [minimize_automaton(automaton.clone()), minimize_automaton_canonical(automaton)] {
+            assert_eq!(minimised.num_states, 0);
+            assert_eq!(minimised.ranked_alphabet["!"], 0);
+            assert_eq!(compute_val_for_tree(minimised, Tree::new("a".to_owned())), 0.0);
+        }
+    }
+}
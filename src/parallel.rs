@@ -0,0 +1,74 @@
+use log::debug;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+/*
+    Parallel computation of independent candidates, used by step I and step II.
+    The candidates are split into one contiguous chunk per thread and the results of the chunks are concatenated in
+    their original order, so the result is the same as the sequential one, no matter how many threads are used.
+    This keeps the chosen basis (and therefore the minimised automaton) reproducible.
+*/
+
+// 0 means that the number of threads hasn't been set, in that case all available cores are used.
+static NUM_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+// Below this number of candidates starting threads costs more than it saves.
+const MIN_CANDIDATES_PER_THREAD: usize = 16;
+
+// Sets the number of threads used by step I and step II, 1 turns the parallelisation off.
+pub fn set_num_threads(num_threads: usize) {
+    NUM_THREADS.store(num_threads.max(1), Ordering::Relaxed);
+}
+
+pub fn num_threads() -> usize {
+    let num_threads = NUM_THREADS.load(Ordering::Relaxed);
+    if num_threads == 0 {
+        return thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    }
+    return num_threads;
+}
+
+// Computes f(item) for all items, the i-th result belongs to the i-th item.
+pub fn parallel_map<T, R, F>(items: &[T], f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    return parallel_map_with_threads(items, num_threads(), f);
+}
+
+// Like parallel_map, but uses at most max_threads threads instead of the global setting.
+fn parallel_map_with_threads<T, R, F>(items: &[T], max_threads: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    let num_threads = max_threads.min(items.len() / MIN_CANDIDATES_PER_THREAD).max(1);
+    if num_threads == 1 {
+        return items.iter().map(f).collect();
+    }
+    debug!("Computing {} candidates with {num_threads} threads", items.len());
+    let chunk_size = items.len().div_ceil(num_threads);
+    return thread::scope(|scope| {
+        let handles: Vec<_> = items
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(|| chunk.iter().map(&f).collect::<Vec<R>>()))
+            .collect();
+        // Joining the handles in the order of the chunks gives the deterministic merge.
+        return handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect();
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parallel_map_keeps_order() {
+        let items: Vec<usize> = (0..1000).collect();
+        let result = parallel_map_with_threads(&items, 4, |x| x * x);
+        assert_eq!(result, items.iter().map(|x| x * x).collect::<Vec<usize>>());
+    }
+}
@@ -1,7 +1,7 @@
 #![allow(dead_code, unused_variables, unused_imports, unused_mut)]
 
 use nalgebra::DMatrix;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 use std::collections::HashSet;
 use std::fs::File;
 use std::io::prelude::*;
@@ -9,6 +9,8 @@ use std::io::BufReader;
 
 use crate::data_structures;
 use crate::data_structures::Production;
+use crate::string_automaton::WeightedStringAutomaton;
+use trees::{Node, Tree};
 
 use log::{debug, info};
 
@@ -26,7 +28,7 @@ pub fn read_bottom_up_grammar(
     path: &str
 ) -> data_structures::WeightedTreeAutomatonStruct {
     debug!("Reading bottom-up grammar");
-    let mut production_rules: HashMap<String, Vec<Production>> = HashMap::new();
+    let mut production_rules: BTreeMap<String, Vec<Production>> = BTreeMap::new();
 
     let grammar_file: File = File::open(path).unwrap();
     let grammar_reader: BufReader<File> = BufReader::new(grammar_file);
@@ -35,12 +37,12 @@ pub fn read_bottom_up_grammar(
     // We create a set for all states:
     let mut state_set = HashSet::new();
     // And a ranked alphabet:
-    let mut ranked_alphabet: HashMap<String, usize> = HashMap::new();
+    let mut ranked_alphabet: BTreeMap<String, usize> = BTreeMap::new();
 
     for line in grammar_reader.lines() {
         let line_copy: String = line.unwrap();
         // Skip emtpy lines!
-        if line_copy == "" {
+        if line_copy.is_empty() {
             continue;
         }
 
@@ -119,8 +121,8 @@ pub fn read_bottom_up_grammar(
 
 
 // This reads grammars of the form which are used by the Berkley parser. For more details please look at https://github.com/slavpetrov/berkeleyparser
-// This code currently has no way to work with top down grammars, but the basic data-structures are build in a way that an extension should be easily possible.
-// Writing a top-down to bottom-up parser should allow the application of the minimisation on top-down automata.
+// The result can be turned into a top-down matrix representation with data_structures::prod_vec_to_top_down_automaton
+// or into a bottom-up automaton with data_structures::top_down_to_bottom_up, both can be minimised.
 // This code can be considered irrelevant for the thesis itself but is included in case that at some point somebody wants to build upon this code.
 
 pub fn read_top_down_automaton(
@@ -128,12 +130,12 @@ pub fn read_top_down_automaton(
     path_lexicon: &str,
 ) -> data_structures::WeightedTreeAutomatonStruct {
 
-    let mut production_rules: HashMap<String, Vec<Production>> = HashMap::new();
+    let mut production_rules: BTreeMap<String, Vec<Production>> = BTreeMap::new();
     // Since we iterate over the grammar anyways, lets save some useful information.
     // We create a set for all states:
     let mut state_set: HashSet<String> = HashSet::new();
     // And a ranked alphabet:
-    let mut ranked_alphabet: HashMap<String, usize> = HashMap::new();
+    let mut ranked_alphabet: BTreeMap<String, usize> = BTreeMap::new();
 
     debug!("Reading top-down grammar");
     let grammar_file: File = File::open(path_grammar).unwrap();
@@ -228,19 +230,323 @@ pub fn read_top_down_automaton(
     return return_value
 }
 
+// The string automaton format follows the bottom-up grammar format:
+// "> q w" gives state q the initial weight w, "a q -> p w" is a transition from q to p reading the letter a with
+// weight w and "! q w" gives state q the final weight w. All other weights are 0.
+pub fn read_string_automaton(path: &str) -> WeightedStringAutomaton {
+    debug!("Reading string automaton");
+    let file: File = File::open(path).unwrap();
+    let reader: BufReader<File> = BufReader::new(file);
+
+    // Each line is saved as (letter, states, cost) first, since the indices of the states are only known at the end.
+    let mut lines: Vec<(String, Vec<String>, f64)> = Vec::new();
+    let mut state_set = HashSet::new();
+    for line in reader.lines() {
+        let line_copy: String = line.unwrap();
+        // Skip emtpy lines!
+        if line_copy.trim().is_empty() {
+            continue;
+        }
+        let parts: Vec<&str> = line_copy.split_whitespace().filter(|part| *part != "->").collect();
+        let letter = parts[0].to_owned();
+        let states: Vec<String> = parts[1..(parts.len() - 1)].iter().map(|state| state.to_string()).collect();
+        let cost: f64 = parts[parts.len() - 1].parse().unwrap();
+        for state in states.iter() {
+            state_set.insert(state.clone());
+        }
+        lines.push((letter, states, cost));
+    }
+
+    // Ordering of states is always expected to be alpha-numerical.
+    let mut ordered_state_list: Vec<String> = state_set.into_iter().collect();
+    ordered_state_list.sort();
+    let index = |state: &String| ordered_state_list.iter().position(|other| other == state).unwrap();
+    let n = ordered_state_list.len();
+
+    let mut initial_weights = DMatrix::from_element(1, n, 0.0);
+    let mut final_weights = DMatrix::from_element(n, 1, 0.0);
+    let mut transition_matrices: BTreeMap<String, DMatrix<f64>> = BTreeMap::new();
+    for (letter, states, cost) in lines {
+        match letter.as_str() {
+            ">" => initial_weights[(0, index(&states[0]))] = cost,
+            "!" => final_weights[(index(&states[0]), 0)] = cost,
+            _ => {
+                let matrix = transition_matrices
+                    .entry(letter)
+                    .or_insert(DMatrix::from_element(n, n, 0.0));
+                matrix[(index(&states[0]), index(&states[1]))] = cost;
+            }
+        }
+    }
+    return WeightedStringAutomaton {
+        num_states: n,
+        initial_weights,
+        transition_matrices,
+        final_weights,
+    };
+}
+
+// Reads one word per line, the letters are separated by whitespace. An empty line is the empty word.
+pub fn read_words(path: &str) -> Vec<Vec<String>> {
+    let file: File = File::open(path).unwrap();
+    let reader: BufReader<File> = BufReader::new(file);
+    return reader
+        .lines()
+        .map(|line| line.unwrap().split_whitespace().map(|letter| letter.to_owned()).collect())
+        .collect();
+}
+
+// Options for read_treebank, all transformations are turned off by default.
+#[derive(Debug, Clone, Default)]
+pub struct TreebankOptions {
+    // NP-SBJ-1 and PP-LOC=2 become NP and PP, labels starting with '-' like -NONE- are kept.
+    pub strip_function_tags: bool,
+    // A node with a single child that isn't a leaf is merged with this child and keeps its own label, so
+    // (S (VP (VB saw))) becomes (S (VB saw)). Preterminals like (VB saw) stay.
+    pub collapse_unary_chains: bool,
+    // A node X with more than two children c_1, ..., c_k becomes X(c_1, @X(c_2, ... @X(c_{k-1}, c_k))).
+    pub binarise: bool,
+}
+
+// Reads trees in the bracket format of the Penn Treebank, for example (S (NP (DT the) (NN dog)) (VP (VB barks))).
+// A file can contain any number of trees, which may span several lines. The outer brackets without a label that
+// surround every tree of the Penn Treebank, ( (S ...) ), are removed. Words are leaves, so (DT the) is the node DT
+// with the leaf the. The trees written by the sample command can be read as well, including trees that consist of a
+// single node, which are written as their bare label.
+pub fn read_treebank(path: &str, options: &TreebankOptions) -> Vec<Tree<String>> {
+    debug!("Reading treebank");
+    let content = std::fs::read_to_string(path).unwrap();
+    let trees = parse_treebank(&content, options);
+    info!("Read {} trees from {path}", trees.len());
+    return trees;
+}
+
+// Reads the trees of a treebank from a string, see read_treebank.
+pub fn parse_treebank(content: &str, options: &TreebankOptions) -> Vec<Tree<String>> {
+    let spaced = content.replace('(', " ( ").replace(')', " ) ");
+    let tokens: Vec<&str> = spaced.split_whitespace().collect();
+
+    fn read_node(tokens: &[&str], position: &mut usize) -> Tree<String> {
+        if tokens[*position] != "(" {
+            let leaf = Tree::new(tokens[*position].to_owned());
+            *position += 1;
+            return leaf;
+        }
+        *position += 1;
+        let mut label = String::new();
+        if *position < tokens.len() && tokens[*position] != "(" && tokens[*position] != ")" {
+            label = tokens[*position].to_owned();
+            *position += 1;
+        }
+        let mut children = Vec::new();
+        loop {
+            if *position >= tokens.len() {
+                panic!("Found '(' without a matching ')' at the end of the treebank.");
+            }
+            if tokens[*position] == ")" {
+                break;
+            }
+            children.push(read_node(tokens, position));
+        }
+        *position += 1;
+        if label.is_empty() {
+            if children.len() != 1 {
+                panic!("A node without a label has to contain exactly one tree, found {}.", children.len());
+            }
+            return children.pop().unwrap();
+        }
+        let mut tree = Tree::new(label);
+        for child in children {
+            tree.push_back(child);
+        }
+        return tree;
+    }
+
+    let mut trees = Vec::new();
+    let mut position = 0;
+    while position < tokens.len() {
+        // A bare token is a tree with a single node, only a closing bracket can't start a tree.
+        if tokens[position] == ")" {
+            panic!("Found ')' without a matching '(' at the start of a tree.");
+        }
+        let mut tree = read_node(&tokens, &mut position);
+        if options.strip_function_tags {
+            tree = strip_function_tags(tree.root());
+        }
+        if options.collapse_unary_chains {
+            tree = collapse_unary_chains(tree.root());
+        }
+        if options.binarise {
+            tree = binarise(tree.root());
+        }
+        debug!("{tree}");
+        trees.push(tree);
+    }
+    return trees;
+}
+
+fn strip_function_tags(node: &Node<String>) -> Tree<String> {
+    let mut label = node.data().clone();
+    // Leaves are words, which are kept as they are.
+    if node.degree() > 0 && !label.starts_with('-') {
+        if let Some(end) = label.find(['-', '=']) {
+            label.truncate(end);
+        }
+    }
+    let mut tree = Tree::new(label);
+    for child in node.iter() {
+        tree.push_back(strip_function_tags(child));
+    }
+    return tree;
+}
+
+// A preterminal is a node whose only child is a leaf, like (VB saw).
+fn is_preterminal(node: &Node<String>) -> bool {
+    return node.degree() == 1 && node.front().unwrap().degree() == 0;
+}
+
+fn collapse_unary_chains(node: &Node<String>) -> Tree<String> {
+    let mut current = node;
+    // Leaves and preterminals are never absorbed, so the chain stops at the node above the preterminal.
+    while current.degree() == 1 && current.front().unwrap().degree() > 0 && !is_preterminal(current.front().unwrap()) {
+        current = current.front().unwrap();
+    }
+    let mut tree = Tree::new(node.data().clone());
+    for child in current.iter() {
+        tree.push_back(collapse_unary_chains(child));
+    }
+    return tree;
+}
+
+fn binarise(node: &Node<String>) -> Tree<String> {
+    let children: Vec<Tree<String>> = node.iter().map(binarise).collect();
+    if children.len() <= 2 {
+        let mut tree = Tree::new(node.data().clone());
+        for child in children {
+            tree.push_back(child);
+        }
+        return tree;
+    }
+    // Build the chain of intermediate nodes from the right.
+    let intermediate_label = format!("@{}", node.data().trim_start_matches('@'));
+    let mut children = children.into_iter().rev();
+    let mut right = children.next().unwrap();
+    let mut left = children.next().unwrap();
+    for child in children {
+        let mut intermediate = Tree::new(intermediate_label.clone());
+        intermediate.push_back(left);
+        intermediate.push_back(right);
+        right = intermediate;
+        left = child;
+    }
+    let mut tree = Tree::new(node.data().clone());
+    tree.push_back(left);
+    tree.push_back(right);
+    return tree;
+}
+
+// Derives the ranked alphabet of a treebank. A label appears with several numbers of children in most treebanks
+// (NP -> DT NN and NP -> NN), so like in data_structures::top_down_to_bottom_up such labels are renamed to "σ/k" in
+// the returned trees. The trees can then be evaluated by automata over the ranked alphabet.
+pub fn treebank_ranked_alphabet(trees: &[Tree<String>]) -> (Vec<Tree<String>>, BTreeMap<String, usize>) {
+    fn ranks_rec(node: &Node<String>, ranks: &mut BTreeMap<String, HashSet<usize>>) {
+        ranks.entry(node.data().clone()).or_default().insert(node.degree());
+        for child in node.iter() {
+            ranks_rec(child, ranks);
+        }
+    }
+    fn rename_rec(node: &Node<String>, ranks: &BTreeMap<String, HashSet<usize>>) -> Tree<String> {
+        let mut label = node.data().clone();
+        if ranks.get(&label).unwrap().len() > 1 {
+            label = format!("{label}/{}", node.degree());
+        }
+        let mut tree = Tree::new(label);
+        for child in node.iter() {
+            tree.push_back(rename_rec(child, ranks));
+        }
+        return tree;
+    }
+    let mut ranks = BTreeMap::new();
+    for tree in trees {
+        ranks_rec(tree.root(), &mut ranks);
+    }
+    let renamed: Vec<Tree<String>> = trees.iter().map(|tree| rename_rec(tree.root(), &ranks)).collect();
+    let ranked_alphabet = data_structures::ranked_alphabet_from_trees(&renamed);
+    return (renamed, ranked_alphabet);
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     #[test]
     fn read_bottom_up_compu_example() {
-        let example_tree_compu = read_bottom_up_grammar("grammar/bottom_up/computation_tree");
+        let example_tree_compu = read_bottom_up_grammar("automata/bottom_up/computation_tree");
     }
     #[test]
     fn read_bottom_up_counter_example() {
-        let example_tree_compu = read_bottom_up_grammar("grammar/bottom_up/counter_tree");
+        let example_tree_compu = read_bottom_up_grammar("automata/bottom_up/counter_tree");
     }
     #[test]
     fn read_bottom_up_rgb_example() {
-        let example_tree_compu = read_bottom_up_grammar("grammar/bottom_up/rgb_tree");
+        let example_tree_compu = read_bottom_up_grammar("automata/bottom_up/rgb_tree");
+    }
+    #[test]
+    fn read_treebank_example() {
+        let brackets = |trees: Vec<Tree<String>>| -> Vec<String> {
+            return trees.iter().map(data_structures::tree_to_brackets).collect();
+        };
+        let raw = brackets(read_treebank("automata/treebank/wsj_sample.mrg", &TreebankOptions::default()));
+        assert_eq!(raw.len(), 3);
+        assert_eq!(raw[2], "(S (NP-SBJ=2 (-NONE- *T*-1)) (VP (VB saw)))");
+
+        let options = TreebankOptions {
+            strip_function_tags: true,
+            collapse_unary_chains: true,
+            binarise: true,
+        };
+        let trees = brackets(read_treebank("automata/treebank/wsj_sample.mrg", &options));
+        assert_eq!(
+            trees[0],
+            "(S (NP (DT the) (NN dog)) (@S (VP (VB saw) (NP (DT the) (@NP (JJ big) (NN telescope)))) (. .)))"
+        );
+        assert_eq!(trees[1], "(S (NP (NN dog)) (@S (VP (VB saw) (NP (NN telescope))) (. .)))");
+        assert_eq!(trees[2], "(S (NP (-NONE- *T*-1)) (VP (VB saw)))");
+    }
+
+    #[test]
+    fn collapse_unary_chains_keeps_preterminals() {
+        let options = TreebankOptions { collapse_unary_chains: true, ..TreebankOptions::default() };
+        let trees = parse_treebank("(S (VP (VB saw)))\n(NP (NN dog))", &options);
+        assert_eq!(data_structures::tree_to_brackets(&trees[0]), "(S (VB saw))");
+        assert_eq!(data_structures::tree_to_brackets(&trees[1]), "(NP (NN dog))");
+    }
+
+    #[test]
+    fn treebank_with_single_node_trees() {
+        let trees = vec![Tree::new("dog".to_owned()), Tree::<String>::from_tuple(("NP".to_owned(), "dog".to_owned()))];
+        let content: Vec<String> = trees.iter().map(data_structures::tree_to_brackets).collect();
+        assert_eq!(parse_treebank(&content.join("\n"), &TreebankOptions::default()), trees);
+    }
+
+    #[test]
+    fn treebank_with_pcfg() {
+        let (trees, ranked_alphabet) =
+            treebank_ranked_alphabet(&read_treebank("automata/treebank/simple_pcfg.mrg", &TreebankOptions::default()));
+        assert_eq!(ranked_alphabet.get("NP/2"), Some(&2));
+        assert_eq!(ranked_alphabet.get("NP/1"), Some(&1));
+        assert_eq!(ranked_alphabet.get("dog"), Some(&0));
+        let grammar = read_top_down_automaton(
+            "automata/top_down/simple_pcfg.grammar",
+            "automata/top_down/simple_pcfg.lexicon",
+        );
+        let automaton = data_structures::prod_vec_to_automaton(data_structures::top_down_to_bottom_up(&grammar, "ROOT_0"));
+        let weight = crate::computation::compute_val_for_tree(automaton, trees[0].clone());
+        assert!((weight - 0.3 * 0.6 * 0.3 * 0.4).abs() < 1e-12);
+    }
+
+    #[test]
+    #[should_panic(expected = "Found '(' without a matching ')'")]
+    fn treebank_with_missing_parenthesis() {
+        parse_treebank("(S (NP dog)", &TreebankOptions::default());
     }
 }
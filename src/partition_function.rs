@@ -0,0 +1,285 @@
+use crate::data_structures::{self, WeightedTreeAutomatonMatrix, WeightedTreeAutomatonStruct};
+use nalgebra::DMatrix;
+use log::{debug, info, warn};
+
+type DynamicMatrix = DMatrix<f64>;
+
+// Two iterates whose entries differ by less than this value are considered equal.
+const TOLERANCE: f64 = 0.000000001;
+// If an entry of an iterate grows beyond this value we consider the iteration to be divergent.
+const DIVERGENCE_BOUND: f64 = 1e15;
+const MAX_ITERATIONS: usize = 100000;
+
+/*
+    The sum of the weights of all trees Σ_t ||A||(t) = x γ, where x = Σ_t μ(t) is the least fixed point of the
+    polynomial system
+    x = Σ_σ (x ⊗ ... ⊗ x) μ_σ,
+    with a k-fold kronecker product for σ of rank k (for leaves the summand is simply μ_σ).
+    We offer two ways to compute it, both start at x_0 = 0:
+    Kleene iteration x_{i+1} = F(x_i), which is simple but can converge very slowly, and
+    Newton's method x_{i+1} = x_i + (F(x_i) - x_i)(I - J(x_i))^{-1} with the Jacobian
+    J(x) = Σ_σ Σ_p (x ⊗ ... ⊗ I ⊗ ... ⊗ x) μ_σ, where the identity is at position p.
+    Both methods return None if the sum diverges.
+*/
+
+// Computes F(x) = Σ_σ (x ⊗ ... ⊗ x) μ_σ.
+fn apply_system(automaton_matrix: &WeightedTreeAutomatonMatrix, x: &DynamicMatrix) -> DynamicMatrix {
+    let mut result = DynamicMatrix::from_element(1, automaton_matrix.num_states, 0.0);
+    for (σ, μ_σ) in automaton_matrix.transition_matrices.iter() {
+        if σ == "!" {
+            continue;
+        }
+        let rk_σ = automaton_matrix.ranked_alphabet.get(σ).unwrap();
+        let mut x_kron_k = DynamicMatrix::from_element(1, 1, 1.0);
+        for _ in 0..*rk_σ {
+            x_kron_k = x_kron_k.kronecker(x);
+        }
+        result += x_kron_k * μ_σ;
+    }
+    return result;
+}
+
+// Computes the Jacobian J(x) = Σ_σ Σ_p (x ⊗ ... ⊗ I ⊗ ... ⊗ x) μ_σ.
+fn jacobian(automaton_matrix: &WeightedTreeAutomatonMatrix, x: &DynamicMatrix) -> DynamicMatrix {
+    let n = automaton_matrix.num_states;
+    let identity = DynamicMatrix::identity(n, n);
+    let mut result = DynamicMatrix::from_element(n, n, 0.0);
+    for (σ, μ_σ) in automaton_matrix.transition_matrices.iter() {
+        if σ == "!" {
+            continue;
+        }
+        let rk_σ = automaton_matrix.ranked_alphabet.get(σ).unwrap();
+        for identity_position in 0..*rk_σ {
+            let mut derivative = DynamicMatrix::from_element(1, 1, 1.0);
+            for position in 0..*rk_σ {
+                if position == identity_position {
+                    derivative = derivative.kronecker(&identity);
+                } else {
+                    derivative = derivative.kronecker(x);
+                }
+            }
+            result += derivative * μ_σ;
+        }
+    }
+    return result;
+}
+
+fn has_diverged(x: &DynamicMatrix) -> bool {
+    return x.iter().any(|value| !value.is_finite() || value.abs() > DIVERGENCE_BOUND);
+}
+
+// Computes x = Σ_t μ(t) with Kleene iteration.
+pub fn kleene_iteration(automaton_matrix: &WeightedTreeAutomatonMatrix) -> Option<DynamicMatrix> {
+    automaton_matrix.assert_bottom_up("kleene_iteration");
+    let mut x = DynamicMatrix::from_element(1, automaton_matrix.num_states, 0.0);
+    for iteration in 0..MAX_ITERATIONS {
+        let new_x = apply_system(automaton_matrix, &x);
+        if has_diverged(&new_x) {
+            warn!("Kleene iteration diverged after {iteration} iterations");
+            return None;
+        }
+        if (&new_x - &x).amax() < TOLERANCE {
+            debug!("Kleene iteration converged after {iteration} iterations: {new_x}");
+            return Some(new_x);
+        }
+        x = new_x;
+    }
+    warn!("Kleene iteration did not converge after {MAX_ITERATIONS} iterations");
+    return None;
+}
+
+// Computes x = Σ_t μ(t) with Newton's method.
+pub fn newton_iteration(automaton_matrix: &WeightedTreeAutomatonMatrix) -> Option<DynamicMatrix> {
+    automaton_matrix.assert_bottom_up("newton_iteration");
+    let n = automaton_matrix.num_states;
+    let identity = DynamicMatrix::identity(n, n);
+    let mut x = DynamicMatrix::from_element(1, n, 0.0);
+    for iteration in 0..MAX_ITERATIONS {
+        let difference = apply_system(automaton_matrix, &x) - &x;
+        if difference.amax() < TOLERANCE {
+            debug!("Newton's method converged after {iteration} iterations: {x}");
+            return Some(x);
+        }
+        // If I - J(x) is singular, the sum is at best critical and Newton's method can't continue.
+        let inverse = match (&identity - jacobian(automaton_matrix, &x)).try_inverse() {
+            Some(inverse) => inverse,
+            None => {
+                warn!("Newton's method stopped after {iteration} iterations, I - J(x) is singular");
+                return None;
+            }
+        };
+        x += difference * inverse;
+        if has_diverged(&x) {
+            warn!("Newton's method diverged after {iteration} iterations");
+            return None;
+        }
+    }
+    warn!("Newton's method did not converge after {MAX_ITERATIONS} iterations");
+    return None;
+}
+
+// Computes Σ_t ||A||(t) with Newton's method, or returns None if the sum diverges.
+pub fn total_weight(automaton_matrix: &WeightedTreeAutomatonMatrix) -> Option<f64> {
+    let x = newton_iteration(automaton_matrix)?;
+    let γ = automaton_matrix.transition_matrices.get("!").unwrap();
+    let result = (x * γ)[(0, 0)];
+    info!("Total weight: {result}");
+    return Some(result);
+}
+
+// A PCFG is consistent if the probabilities of all trees that can be derived from the root state sum up to 1.
+pub fn is_consistent_pcfg(grammar: &WeightedTreeAutomatonStruct, root_state: &str) -> bool {
+    let bottom_up = data_structures::top_down_to_bottom_up(grammar, root_state);
+    let automaton = data_structures::prod_vec_to_automaton(bottom_up);
+    return match total_weight(&automaton) {
+        Some(weight) => (weight - 1.0).abs() < 0.000001,
+        None => false,
+    };
+}
+
+/*
+    Instead of summing over all trees, we can also sum over the finitely many trees of bounded height or size.
+    If leaves have height 1, the trees of height at most h are exactly the trees built in h steps of the Kleene
+    iteration, so their sum is x_h = F^h(0).
+    For the trees with exactly m nodes let y_m = Σ_{|t| = m} μ(t). Then y_1 = Σ μ_σ over all leaves σ and
+    y_m = Σ_σ Σ_{m_1 + ... + m_k = m - 1} (y_{m_1} ⊗ ... ⊗ y_{m_k}) μ_σ.
+    The inner sums are computed by the dynamic program P_0[0] = [1], P_j[l] = Σ_a P_{j-1}[l - a] ⊗ y_a,
+    whose entries have the same row layout as the kronecker products in prod_vec_to_automaton.
+*/
+
+// Computes Σ ||A||(t) over all trees of height at most h.
+pub fn weight_up_to_height(automaton_matrix: &WeightedTreeAutomatonMatrix, h: usize) -> f64 {
+    let mut x = DynamicMatrix::from_element(1, automaton_matrix.num_states, 0.0);
+    for _ in 0..h {
+        x = apply_system(automaton_matrix, &x);
+    }
+    let γ = automaton_matrix.transition_matrices.get("!").unwrap();
+    return (x * γ)[(0, 0)];
+}
+
+// Computes y_m = Σ_{|t| = m} μ(t) for all m in 0..(max_size + 1). There are no trees without nodes, so y_0 = 0.
+pub fn vectors_by_size(automaton_matrix: &WeightedTreeAutomatonMatrix, max_size: usize) -> Vec<DynamicMatrix> {
+    automaton_matrix.assert_bottom_up("vectors_by_size");
+    let n = automaton_matrix.num_states;
+    let max_rank = automaton_matrix
+        .transition_matrices
+        .keys()
+        .filter(|σ| *σ != "!")
+        .map(|σ| *automaton_matrix.ranked_alphabet.get(σ).unwrap())
+        .max()
+        .unwrap_or(0);
+    let mut y = vec![DynamicMatrix::from_element(1, n, 0.0); max_size + 1];
+    // p[j][l] = Σ_{m_1 + ... + m_j = l} y_{m_1} ⊗ ... ⊗ y_{m_j}, only filled for l < m when y_m is computed.
+    let mut p: Vec<Vec<DynamicMatrix>> = Vec::new();
+    for j in 0..(max_rank + 1) {
+        p.push(vec![DynamicMatrix::from_element(1, n.pow(j as u32), 0.0); max_size + 1]);
+    }
+    p[0][0] = DynamicMatrix::from_element(1, 1, 1.0);
+
+    for m in 1..(max_size + 1) {
+        for (σ, μ_σ) in automaton_matrix.transition_matrices.iter() {
+            if σ == "!" {
+                continue;
+            }
+            let rk_σ = *automaton_matrix.ranked_alphabet.get(σ).unwrap();
+            y[m] += &p[rk_σ][m - 1] * μ_σ;
+        }
+        // y_m is done, so we can now extend the tuples whose sizes add up to m.
+        for j in 1..(max_rank + 1) {
+            let mut p_j_m = DynamicMatrix::from_element(1, n.pow(j as u32), 0.0);
+            for a in 1..(m + 1) {
+                p_j_m += p[j - 1][m - a].kronecker(&y[a]);
+            }
+            p[j][m] = p_j_m;
+        }
+    }
+    return y;
+}
+
+// Computes Σ ||A||(t) over all trees with exactly m nodes for all m in 0..(max_size + 1).
+pub fn weights_by_size(automaton_matrix: &WeightedTreeAutomatonMatrix, max_size: usize) -> Vec<f64> {
+    let γ = automaton_matrix.transition_matrices.get("!").unwrap();
+    return vectors_by_size(automaton_matrix, max_size)
+        .into_iter()
+        .map(|y_m| (y_m * γ)[(0, 0)])
+        .collect();
+}
+
+// A cheap approximate equivalence check: Two equivalent automata have the same weight sums for every size.
+// The converse doesn't hold, so this can only be used to find differences.
+pub fn agree_up_to_size(
+    a: &WeightedTreeAutomatonMatrix,
+    b: &WeightedTreeAutomatonMatrix,
+    max_size: usize,
+) -> bool {
+    let weights_a = weights_by_size(a, max_size);
+    let weights_b = weights_by_size(b, max_size);
+    for m in 0..(max_size + 1) {
+        if (weights_a[m] - weights_b[m]).abs() > 0.000001 * (1.0 + weights_a[m].abs()) {
+            debug!("The automata differ on trees of size {m}: {} != {}", weights_a[m], weights_b[m]);
+            return false;
+        }
+    }
+    return true;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    #[test]
+    fn consistent_pcfg() {
+        let grammar = parser::read_top_down_automaton(
+            "automata/top_down/simple_pcfg.grammar",
+            "automata/top_down/simple_pcfg.lexicon",
+        );
+        assert!(is_consistent_pcfg(&grammar, "ROOT_0"));
+    }
+
+    #[test]
+    fn inconsistent_pcfg() {
+        // S -> S S (0.7) | a (0.3) has the least fixed point Z = 0.7 Z^2 + 0.3 = 3/7.
+        let grammar = parser::read_top_down_automaton(
+            "automata/top_down/inconsistent_pcfg.grammar",
+            "automata/top_down/inconsistent_pcfg.lexicon",
+        );
+        assert!(!is_consistent_pcfg(&grammar, "ROOT_0"));
+        let automaton = data_structures::prod_vec_to_automaton(data_structures::top_down_to_bottom_up(&grammar, "ROOT_0"));
+        let newton = total_weight(&automaton).unwrap();
+        assert!((newton - 3.0 / 7.0).abs() < 0.000001);
+        let γ = automaton.transition_matrices.get("!").unwrap();
+        let kleene = (kleene_iteration(&automaton).unwrap() * γ)[(0, 0)];
+        assert!((kleene - 3.0 / 7.0).abs() < 0.0001);
+    }
+
+    #[test]
+    fn divergent_sum() {
+        // The counter automaton gives weight 1 to infinitely many trees, e.g. s(a, s(b, a)).
+        let automaton = data_structures::prod_vec_to_automaton(
+            parser::read_bottom_up_grammar("automata/bottom_up/counter_tree"),
+        );
+        assert!(total_weight(&automaton).is_none());
+        assert!(kleene_iteration(&automaton).is_none());
+    }
+
+    #[test]
+    fn bounded_sums_computation_example() {
+        let automaton = data_structures::prod_vec_to_automaton(
+            parser::read_bottom_up_grammar("automata/bottom_up/computation_tree"),
+        );
+        // Size 1: 0 and 1. Size 3: all of +, -, x applied to two leaves, which sum up to 4, 0 and 1.
+        let weights = weights_by_size(&automaton, 5);
+        assert_eq!(weights[0], 0.0);
+        assert!((weights[1] - 1.0).abs() < 0.000001);
+        assert!((weights[2] - 0.0).abs() < 0.000001);
+        assert!((weights[3] - 5.0).abs() < 0.000001);
+        assert!((weight_up_to_height(&automaton, 2) - 6.0).abs() < 0.000001);
+
+        let automaton_times_two = data_structures::prod_vec_to_automaton(
+            parser::read_bottom_up_grammar("automata/bottom_up/computation_tree_times_two"),
+        );
+        assert!(agree_up_to_size(&automaton, &automaton, 5));
+        assert!(!agree_up_to_size(&automaton, &automaton_times_two, 5));
+    }
+}
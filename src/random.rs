@@ -0,0 +1,77 @@
+/*
+    A small seedable pseudo random number generator (SplitMix64). It is used for sampling trees and for generating
+    random automata, where we need reproducible results for a given seed but no cryptographic quality.
+*/
+
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Random {
+        return Random { state: seed };
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        return z ^ (z >> 31);
+    }
+
+    // A uniformly distributed value in [0, 1).
+    pub fn next_f64(&mut self) -> f64 {
+        // The upper 53 bits fill the mantissa of the f64.
+        return (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+    }
+
+    // A uniformly distributed value in 0..n.
+    pub fn next_usize(&mut self, n: usize) -> usize {
+        return (self.next_f64() * n as f64) as usize;
+    }
+
+    // A uniformly distributed value in [low, high).
+    pub fn uniform(&mut self, low: f64, high: f64) -> f64 {
+        return low + (high - low) * self.next_f64();
+    }
+
+    // A normally distributed value, computed with the Box-Muller transform.
+    pub fn normal(&mut self, mean: f64, standard_deviation: f64) -> f64 {
+        let u_1 = 1.0 - self.next_f64();
+        let u_2 = self.next_f64();
+        let z = (-2.0 * u_1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u_2).cos();
+        return mean + standard_deviation * z;
+    }
+
+    // Picks an index with a probability proportional to its weight. The weights must not be negative.
+    // Returns None if no weight is positive, since then there is nothing to choose from.
+    pub fn choose_weighted(&mut self, weights: &[f64]) -> Option<usize> {
+        let total: f64 = weights.iter().sum();
+        if total <= 0.0 {
+            return None;
+        }
+        let mut threshold = self.next_f64() * total;
+        for (index, weight) in weights.iter().enumerate() {
+            if threshold < *weight {
+                return Some(index);
+            }
+            threshold -= weight;
+        }
+        // Rounding errors can leave a tiny rest, in that case we take the last index with a positive weight.
+        return weights.iter().rposition(|weight| *weight > 0.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn choose_weighted_zero_weights() {
+        let mut random = Random::new(0);
+        assert_eq!(random.choose_weighted(&[0.0, 0.0]), None);
+        assert_eq!(random.choose_weighted(&[]), None);
+        assert_eq!(random.choose_weighted(&[0.0, 2.0, 0.0]), Some(1));
+    }
+}
@@ -0,0 +1,304 @@
+use crate::data_structures::{
+    automaton_to_prod_vec, GrammarType, WeightedTreeAutomatonMatrix, WeightedTreeAutomatonStruct, CONTEXT_HOLE,
+};
+use crate::hankel::hankel_matrix;
+use crate::random::Random;
+use crate::step_i::step_i_forward_with_trees;
+use nalgebra::DMatrix;
+use log::{debug, info};
+use std::collections::BTreeMap;
+use trees::Tree;
+
+type DynamicMatrix = DMatrix<f64>;
+
+// All singular values below eps are considered equal to 0 when used in numeric algorithms.
+const EPS: f64 = 0.00001;
+// Number of times the core automaton is generated again if it isn't minimal.
+const MAX_ATTEMPTS: usize = 100;
+// Maximal height of the random contexts used to check that the core automaton is minimal.
+const CONTEXT_HEIGHT: usize = 3;
+
+/*
+    Random automata with a known minimal size.
+    First we generate a core automaton with minimal_states states and random weights and check that it is minimal:
+    the rank of its Hankel matrix (see hankel.rs) is a lower bound for the minimal size, so a rank of minimal_states
+    proves it. Then we plant redundancy by adding two kinds of states that don't change the computed tree series:
+    - dead states, which are reached by trees but have final weight 0 and only lead to other dead states and
+    - unreachable states, which are never reached by any tree.
+    The core states only depend on core states, so the first minimal_states entries of μ(t) are the ones of the core
+    automaton. Finally a random change of basis mixes all states, so that the redundancy isn't visible in the
+    transition matrices anymore. The result has num_states states and a minimal automaton with minimal_states states.
+*/
+
+#[derive(Debug, Clone)]
+pub enum WeightDistribution {
+    Uniform { low: f64, high: f64 },
+    Normal { mean: f64, standard_deviation: f64 },
+}
+
+impl WeightDistribution {
+    fn sample(&self, random: &mut Random) -> f64 {
+        match self {
+            WeightDistribution::Uniform { low, high } => random.uniform(*low, *high),
+            WeightDistribution::Normal { mean, standard_deviation } => random.normal(*mean, *standard_deviation),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RandomAutomatonConfig {
+    pub num_states: usize,
+    // Number of states of the minimal automaton, at most num_states.
+    pub minimal_states: usize,
+    // Needs at least one symbol of rank 0, the final weight vector "!" is added automatically.
+    pub ranked_alphabet: BTreeMap<String, usize>,
+    // Probability that a transition of the core automaton has a non-zero weight.
+    pub density: f64,
+    pub weight_distribution: WeightDistribution,
+    pub seed: u64,
+}
+
+impl RandomAutomatonConfig {
+    // A small default setting: the symbols a, b (rank 0), g (rank 1), s (rank 2) and weights uniform in [-1, 1].
+    pub fn new(num_states: usize, minimal_states: usize, seed: u64) -> RandomAutomatonConfig {
+        let mut ranked_alphabet = BTreeMap::new();
+        ranked_alphabet.insert("a".to_owned(), 0);
+        ranked_alphabet.insert("b".to_owned(), 0);
+        ranked_alphabet.insert("g".to_owned(), 1);
+        ranked_alphabet.insert("s".to_owned(), 2);
+        return RandomAutomatonConfig {
+            num_states,
+            minimal_states,
+            ranked_alphabet,
+            density: 1.0,
+            weight_distribution: WeightDistribution::Uniform { low: -1.0, high: 1.0 },
+            seed,
+        };
+    }
+}
+
+// Generates a bottom-up automaton as described above, see random_automaton_matrix.
+pub fn random_automaton(config: &RandomAutomatonConfig) -> WeightedTreeAutomatonStruct {
+    return automaton_to_prod_vec(&random_automaton_matrix(config));
+}
+
+// Generates the matrix representation of a random automaton with config.num_states states whose minimal automaton
+// has exactly config.minimal_states states. Panics if no minimal core automaton is found, for example because the
+// alphabet only has symbols of rank 0 or the density is too low.
+pub fn random_automaton_matrix(config: &RandomAutomatonConfig) -> WeightedTreeAutomatonMatrix {
+    let n = config.num_states;
+    let m = config.minimal_states;
+    if m > n {
+        panic!("The minimal automaton can't have more states ({m}) than the automaton ({n}).");
+    }
+    if !config.ranked_alphabet.iter().any(|(σ, rk_σ)| *rk_σ == 0 && σ != "!") {
+        panic!("The ranked alphabet needs at least one symbol of rank 0.");
+    }
+    let mut ranked_alphabet = config.ranked_alphabet.clone();
+    ranked_alphabet.insert("!".to_owned(), n);
+    let mut random = Random::new(config.seed);
+
+    let mut core = None;
+    for attempt in 0..MAX_ATTEMPTS {
+        let candidate = random_core_automaton(config, &mut random);
+        if m == 0 || is_minimal(&candidate, &mut random) {
+            debug!("Found a minimal core automaton after {} attempt(s)", attempt + 1);
+            core = Some(candidate);
+            break;
+        }
+    }
+    let core = core.unwrap_or_else(|| panic!("Couldn't generate a minimal automaton with {m} states."));
+
+    // States 0..m are the core states, m..m + num_dead the dead states and the rest are unreachable.
+    let num_dead = (n - m).div_ceil(2);
+    let is_core = |q: usize| q < m;
+    let is_dead = |q: usize| m <= q && q < m + num_dead;
+    let is_unreachable = |q: usize| m + num_dead <= q;
+
+    let mut transition_matrices = BTreeMap::new();
+    for (σ, rk_σ) in ranked_alphabet.iter() {
+        if σ == "!" {
+            // Only the core states and the unreachable states have a final weight.
+            let mut γ = DynamicMatrix::from_element(n, 1, 0.0);
+            for q in 0..n {
+                if is_core(q) {
+                    γ[(q, 0)] = core.transition_matrices.get("!").unwrap()[(q, 0)];
+                } else if is_unreachable(q) {
+                    γ[(q, 0)] = config.weight_distribution.sample(&mut random);
+                }
+            }
+            transition_matrices.insert(σ.clone(), γ);
+            continue;
+        }
+        let core_μ_σ = core.transition_matrices.get(σ).unwrap();
+        let mut μ_σ = DynamicMatrix::from_element(n.pow(*rk_σ as u32), n, 0.0);
+        for row in 0..μ_σ.nrows() {
+            let child_states: Vec<usize> = (0..*rk_σ)
+                .map(|position| (row / n.pow((rk_σ - 1 - position) as u32)) % n)
+                .collect();
+            if child_states.iter().all(|q| is_core(*q)) {
+                // Copy the row of the core automaton and let the core states also reach dead states.
+                let core_row = child_states.iter().fold(0, |core_row, q| core_row * m + q);
+                for q in 0..n {
+                    if is_core(q) {
+                        μ_σ[(row, q)] = core_μ_σ[(core_row, q)];
+                    } else if is_dead(q) {
+                        μ_σ[(row, q)] = config.weight_distribution.sample(&mut random);
+                    }
+                }
+            } else if child_states.iter().any(|q| is_unreachable(*q)) {
+                // These rows are never used, so they may lead anywhere.
+                for q in 0..n {
+                    μ_σ[(row, q)] = config.weight_distribution.sample(&mut random);
+                }
+            } else {
+                // At least one dead child and no unreachable child: only dead states follow.
+                for q in 0..n {
+                    if is_dead(q) {
+                        μ_σ[(row, q)] = config.weight_distribution.sample(&mut random);
+                    }
+                }
+            }
+        }
+        transition_matrices.insert(σ.clone(), μ_σ);
+    }
+    let automaton_matrix = WeightedTreeAutomatonMatrix {
+        num_states: n,
+        grammar_type: GrammarType::BottomUp,
+        ranked_alphabet,
+        transition_matrices,
+    };
+    if n == m {
+        return automaton_matrix;
+    }
+
+    // Mix the states with a random invertible matrix.
+    loop {
+        let p = DynamicMatrix::from_fn(n, n, |_, _| random.normal(0.0, 1.0));
+        if p.clone().svd(false, false).singular_values.min() > 0.1 {
+            info!("Generated an automaton with {n} states and a minimal automaton with {m} states");
+            return automaton_matrix.change_basis(&p);
+        }
+    }
+}
+
+// Generates an automaton with config.minimal_states states in which every weight is non-zero with probability
+// config.density.
+fn random_core_automaton(config: &RandomAutomatonConfig, random: &mut Random) -> WeightedTreeAutomatonMatrix {
+    let m = config.minimal_states;
+    let mut ranked_alphabet = config.ranked_alphabet.clone();
+    ranked_alphabet.insert("!".to_owned(), m);
+    let mut transition_matrices = BTreeMap::new();
+    for (σ, rk_σ) in ranked_alphabet.iter() {
+        let shape = if σ == "!" { (m, 1) } else { (m.pow(*rk_σ as u32), m) };
+        let μ_σ = DynamicMatrix::from_fn(shape.0, shape.1, |_, _| {
+            if random.next_f64() < config.density {
+                return config.weight_distribution.sample(random);
+            }
+            return 0.0;
+        });
+        transition_matrices.insert(σ.clone(), μ_σ);
+    }
+    return WeightedTreeAutomatonMatrix {
+        num_states: m,
+        grammar_type: GrammarType::BottomUp,
+        ranked_alphabet,
+        transition_matrices,
+    };
+}
+
+// Checks that the Hankel matrix of the spanning trees of step I and some random contexts has full rank.
+fn is_minimal(automaton_matrix: &WeightedTreeAutomatonMatrix, random: &mut Random) -> bool {
+    let m = automaton_matrix.num_states;
+    let (_, spanning_trees) = step_i_forward_with_trees(automaton_matrix);
+    if spanning_trees.len() < m {
+        return false;
+    }
+    let contexts: Vec<Tree<String>> = (0..4 * m)
+        .map(|_| random_context(random, &automaton_matrix.ranked_alphabet, CONTEXT_HEIGHT))
+        .collect();
+    let h = hankel_matrix(automaton_matrix, &spanning_trees, &contexts);
+    return h.rank(EPS) == m;
+}
+
+// Generates a random tree of height at most max_height. Every node gets a symbol chosen uniformly at random,
+// only at the maximal height the symbols are restricted to the ones of rank 0.
+pub fn random_tree(random: &mut Random, ranked_alphabet: &BTreeMap<String, usize>, max_height: usize) -> Tree<String> {
+    let symbols: Vec<(&String, &usize)> = ranked_alphabet
+        .iter()
+        .filter(|(σ, rk_σ)| *σ != "!" && (max_height > 0 || **rk_σ == 0))
+        .collect();
+    let (σ, rk_σ) = symbols[random.next_usize(symbols.len())];
+    let mut tree = Tree::new(σ.clone());
+    for _ in 0..*rk_σ {
+        tree.push_back(random_tree(random, ranked_alphabet, max_height - 1));
+    }
+    return tree;
+}
+
+// Generates a random context (see data_structures::plug_into_context) whose hole is at a depth of at most
+// max_height. The nodes on the path to the hole get a symbol of rank at least 1, all other subtrees are random trees.
+pub fn random_context(
+    random: &mut Random,
+    ranked_alphabet: &BTreeMap<String, usize>,
+    max_height: usize,
+) -> Tree<String> {
+    let symbols: Vec<(&String, &usize)> = ranked_alphabet
+        .iter()
+        .filter(|(σ, rk_σ)| *σ != "!" && **rk_σ > 0)
+        .collect();
+    let depth = random.next_usize(max_height + 1);
+    if depth == 0 || symbols.is_empty() {
+        return Tree::new(CONTEXT_HOLE.to_owned());
+    }
+    return random_context_of_depth(random, ranked_alphabet, &symbols, depth, max_height);
+}
+
+fn random_context_of_depth(
+    random: &mut Random,
+    ranked_alphabet: &BTreeMap<String, usize>,
+    symbols: &[(&String, &usize)],
+    depth: usize,
+    max_height: usize,
+) -> Tree<String> {
+    if depth == 0 {
+        return Tree::new(CONTEXT_HOLE.to_owned());
+    }
+    let (σ, rk_σ) = symbols[random.next_usize(symbols.len())];
+    let hole_position = random.next_usize(*rk_σ);
+    let mut context = Tree::new(σ.clone());
+    for position in 0..*rk_σ {
+        if position == hole_position {
+            context.push_back(random_context_of_depth(random, ranked_alphabet, symbols, depth - 1, max_height - 1));
+        } else {
+            context.push_back(random_tree(random, ranked_alphabet, max_height - 1));
+        }
+    }
+    return context;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::computation::compute_val_for_tree;
+    use crate::data_structures::prod_vec_to_automaton;
+    use crate::hankel::hankel_rank;
+
+    #[test]
+    fn planted_minimal_size() {
+        let config = RandomAutomatonConfig::new(5, 3, 1);
+        let automaton_matrix = random_automaton_matrix(&config);
+        assert_eq!(automaton_matrix.num_states, 5);
+        assert_eq!(hankel_rank(&automaton_matrix), 3);
+
+        // The same seed gives the same automaton, also after the conversion into productions.
+        let automaton = prod_vec_to_automaton(random_automaton(&config));
+        let mut random = Random::new(2);
+        for _ in 0..20 {
+            let tree = random_tree(&mut random, &config.ranked_alphabet, 4);
+            let val = compute_val_for_tree(automaton_matrix.clone(), tree.clone());
+            let val_struct = compute_val_for_tree(automaton.clone(), tree);
+            assert!((val - val_struct).abs() <= EPS * val.abs().max(1.0));
+        }
+    }
+}
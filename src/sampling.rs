@@ -0,0 +1,164 @@
+use crate::data_structures::WeightedTreeAutomatonMatrix;
+use crate::partition_function::newton_iteration;
+use crate::random::Random;
+use log::{debug, warn};
+use trees::Tree;
+
+/*
+    Sampling trees t with probability ||A||(t) / Σ_s ||A||(s) from a bottom-up automaton with non-negative weights.
+    Let x = Σ_t μ(t) be the vector of inside weights (see partition_function.rs). We sample top-down:
+    The root state q is chosen with probability x_q γ_q / x γ. A node in state q gets the symbol σ and the child
+    states (q_1, ..., q_k) with probability μ_σ[(q_1, ..., q_k), q] x_{q_1} ... x_{q_k} / x_q, and the children are
+    sampled in the same way. Multiplying these probabilities the inside weights cancel out and we get
+    μ(t) γ / x γ. For a normalised automaton x γ = 1, so t is drawn with probability ||A||(t).
+*/
+
+// One possible way to expand a node in a given state.
+struct Expansion {
+    symbol: String,
+    child_states: Vec<usize>,
+}
+
+pub struct TreeSampler {
+    // expansions[q] and expansion_weights[q] contain all expansions of state q with a non-zero probability.
+    expansions: Vec<Vec<Expansion>>,
+    expansion_weights: Vec<Vec<f64>>,
+    root_weights: Vec<f64>,
+    random: Random,
+}
+
+impl TreeSampler {
+    // Panics if the automaton has negative weights or if the sum of all weights diverges.
+    // Top-down automata are sampled through their bottom-up form.
+    pub fn new(automaton_matrix: &WeightedTreeAutomatonMatrix, seed: u64) -> TreeSampler {
+        let automaton_matrix = &automaton_matrix.to_bottom_up();
+        let n = automaton_matrix.num_states;
+        for (σ, μ_σ) in automaton_matrix.transition_matrices.iter() {
+            if μ_σ.iter().any(|weight| *weight < 0.0) {
+                panic!("Can't sample from an automaton with negative weights (in {σ}).");
+            }
+        }
+        let x = newton_iteration(automaton_matrix)
+            .expect("Can't sample from an automaton whose total weight diverges.");
+        let γ = automaton_matrix.transition_matrices.get("!").unwrap();
+        let total_weight = (&x * γ)[(0, 0)];
+        if (total_weight - 1.0).abs() > 0.000001 {
+            warn!("The automaton isn't normalised (total weight {total_weight}), the probabilities are rescaled.");
+        }
+
+        let mut expansions: Vec<Vec<Expansion>> = (0..n).map(|_| Vec::new()).collect();
+        let mut expansion_weights: Vec<Vec<f64>> = vec![Vec::new(); n];
+        for (σ, μ_σ) in automaton_matrix.transition_matrices.iter() {
+            if σ == "!" {
+                continue;
+            }
+            let rk_σ = *automaton_matrix.ranked_alphabet.get(σ).unwrap();
+            for row in 0..μ_σ.nrows() {
+                // Decode the child states of the row, the first child is the most significant digit.
+                let mut child_states = Vec::new();
+                let mut inside_product = 1.0;
+                for position in 0..rk_σ {
+                    let q = (row / n.pow((rk_σ - 1 - position) as u32)) % n;
+                    inside_product *= x[(0, q)];
+                    child_states.push(q);
+                }
+                for q in 0..n {
+                    let weight = μ_σ[(row, q)] * inside_product;
+                    if weight > 0.0 {
+                        expansions[q].push(Expansion { symbol: σ.clone(), child_states: child_states.clone() });
+                        expansion_weights[q].push(weight);
+                    }
+                }
+            }
+        }
+        let root_weights = (0..n).map(|q| x[(0, q)] * γ[(q, 0)]).collect();
+        debug!("Inside weights: {x}");
+
+        return TreeSampler {
+            expansions,
+            expansion_weights,
+            root_weights,
+            random: Random::new(seed),
+        };
+    }
+
+    // The nodes are sampled in preorder with an explicit stack instead of recursion, so that the deep trees of an
+    // (almost) critical grammar can't overflow the call stack.
+    fn sample_state(&mut self, root_state: usize) -> Tree<String> {
+        let mut symbols: Vec<String> = Vec::new();
+        let mut children: Vec<Vec<usize>> = Vec::new();
+        let mut stack: Vec<(usize, Option<usize>)> = vec![(root_state, None)];
+        while let Some((q, parent)) = stack.pop() {
+            let index = match self.random.choose_weighted(&self.expansion_weights[q]) {
+                Some(index) => index,
+                None => panic!("State {q} has no expansion with a positive weight, so no tree can be sampled from it."),
+            };
+            let expansion = &self.expansions[q][index];
+            let node = symbols.len();
+            symbols.push(expansion.symbol.clone());
+            children.push(Vec::new());
+            if let Some(parent) = parent {
+                children[parent].push(node);
+            }
+            // The first child has to be on top of the stack to keep the preorder.
+            for child_state in expansion.child_states.iter().rev() {
+                stack.push((*child_state, Some(node)));
+            }
+        }
+
+        // Children always come after their parent in preorder, so the trees can be built from the back.
+        let mut subtrees: Vec<Option<Tree<String>>> = (0..symbols.len()).map(|_| None).collect();
+        for node in (0..symbols.len()).rev() {
+            let mut tree = Tree::new(symbols[node].clone());
+            for child in children[node].iter() {
+                tree.push_back(subtrees[*child].take().unwrap());
+            }
+            subtrees[node] = Some(tree);
+        }
+        return subtrees[0].take().unwrap();
+    }
+
+    // Panics if the automaton has total weight 0, since there is no tree to sample then.
+    pub fn sample(&mut self) -> Tree<String> {
+        let root_weights = self.root_weights.clone();
+        let q = match self.random.choose_weighted(&root_weights) {
+            Some(q) => q,
+            None => panic!("Can't sample from an automaton whose total weight is 0."),
+        };
+        return self.sample_state(q);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{computation, data_structures, parser};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn sample_pcfg() {
+        let grammar = parser::read_top_down_automaton(
+            "automata/top_down/simple_pcfg.grammar",
+            "automata/top_down/simple_pcfg.lexicon",
+        );
+        let automaton = data_structures::prod_vec_to_automaton(data_structures::top_down_to_bottom_up(&grammar, "ROOT_0"));
+        let mut sampler = TreeSampler::new(&automaton, 42);
+        let mut counts: BTreeMap<Tree<String>, usize> = BTreeMap::new();
+        let num_samples = 20000;
+        for _ in 0..num_samples {
+            *counts.entry(sampler.sample()).or_insert(0) += 1;
+        }
+        // The most frequent tree should be sampled with roughly its probability.
+        let (tree, count) = counts.iter().max_by_key(|(_, count)| **count).unwrap();
+        let probability = computation::compute_val_for_tree(automaton.clone(), tree.clone());
+        let frequency = *count as f64 / num_samples as f64;
+        assert!((frequency - probability).abs() < 0.02, "{tree}: {frequency} vs. {probability}");
+
+        // The same seed gives the same trees.
+        let mut sampler_one = TreeSampler::new(&automaton, 7);
+        let mut sampler_two = TreeSampler::new(&automaton, 7);
+        for _ in 0..10 {
+            assert_eq!(sampler_one.sample(), sampler_two.sample());
+        }
+    }
+}
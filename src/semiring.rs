@@ -0,0 +1,276 @@
+use crate::data_structures::{GrammarType, Production, WeightedTreeAutomatonStruct};
+use log::debug;
+use std::collections::HashMap;
+use std::fmt;
+use trees::{Node, Tree};
+
+/*
+    Evaluation of trees in an arbitrary semiring, directly on the productions of a WeightedTreeAutomatonStruct.
+    The value of a tree is the semiring sum over all runs of the semiring product of the (converted) costs of the
+    productions used by the run. compute_val_for_tree is the special case of the real semiring, but works on the
+    matrix representation. The minimisation needs a field, so it only exists for the real numbers.
+    - Real: (+, ·), the usual weight of a tree.
+    - Log: the logarithms of the real weights, a ⊕ b = ln(e^a + e^b) and a ⊗ b = a + b.
+    - Tropical: the max-plus semiring over the logarithms of the weights, a ⊕ b = max(a, b) and a ⊗ b = a + b. The
+      value is the log weight of the best run (see compute_viterbi_run_for_tree).
+    - Boolean: (∨, ∧), whether the tree has a run with non-zero weight.
+    - Counting: (+, ·) over the natural numbers, the number of runs with non-zero weight.
+*/
+
+pub trait Semiring: Clone + fmt::Debug {
+    fn zero() -> Self;
+    fn one() -> Self;
+    fn plus(&self, other: &Self) -> Self;
+    fn times(&self, other: &Self) -> Self;
+    // Converts the cost of a production into the semiring.
+    fn from_cost(cost: f64) -> Self;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Real(pub f64);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Log(pub f64);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tropical(pub f64);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Boolean(pub bool);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Counting(pub u64);
+
+impl Semiring for Real {
+    fn zero() -> Self {
+        return Real(0.0);
+    }
+    fn one() -> Self {
+        return Real(1.0);
+    }
+    fn plus(&self, other: &Self) -> Self {
+        return Real(self.0 + other.0);
+    }
+    fn times(&self, other: &Self) -> Self {
+        return Real(self.0 * other.0);
+    }
+    fn from_cost(cost: f64) -> Self {
+        return Real(cost);
+    }
+}
+
+impl Semiring for Log {
+    fn zero() -> Self {
+        return Log(f64::NEG_INFINITY);
+    }
+    fn one() -> Self {
+        return Log(0.0);
+    }
+    fn plus(&self, other: &Self) -> Self {
+        let max = self.0.max(other.0);
+        if max == f64::NEG_INFINITY {
+            return Log::zero();
+        }
+        // Factoring out the maximum avoids overflows of the exponential function.
+        return Log(max + ((self.0 - max).exp() + (other.0 - max).exp()).ln());
+    }
+    fn times(&self, other: &Self) -> Self {
+        return Log(self.0 + other.0);
+    }
+    // Panics for negative costs, since they have no logarithm.
+    fn from_cost(cost: f64) -> Self {
+        if cost < 0.0 {
+            panic!("The cost {cost} is negative and has no logarithm.");
+        }
+        return Log(cost.ln());
+    }
+}
+
+impl Semiring for Tropical {
+    fn zero() -> Self {
+        return Tropical(f64::NEG_INFINITY);
+    }
+    fn one() -> Self {
+        return Tropical(0.0);
+    }
+    fn plus(&self, other: &Self) -> Self {
+        return Tropical(self.0.max(other.0));
+    }
+    fn times(&self, other: &Self) -> Self {
+        return Tropical(self.0 + other.0);
+    }
+    // Panics for negative costs, since they have no logarithm.
+    fn from_cost(cost: f64) -> Self {
+        if cost < 0.0 {
+            panic!("The cost {cost} is negative and has no logarithm.");
+        }
+        return Tropical(cost.ln());
+    }
+}
+
+impl Semiring for Boolean {
+    fn zero() -> Self {
+        return Boolean(false);
+    }
+    fn one() -> Self {
+        return Boolean(true);
+    }
+    fn plus(&self, other: &Self) -> Self {
+        return Boolean(self.0 || other.0);
+    }
+    fn times(&self, other: &Self) -> Self {
+        return Boolean(self.0 && other.0);
+    }
+    fn from_cost(cost: f64) -> Self {
+        return Boolean(cost != 0.0);
+    }
+}
+
+impl Semiring for Counting {
+    fn zero() -> Self {
+        return Counting(0);
+    }
+    fn one() -> Self {
+        return Counting(1);
+    }
+    fn plus(&self, other: &Self) -> Self {
+        return Counting(self.0 + other.0);
+    }
+    fn times(&self, other: &Self) -> Self {
+        return Counting(self.0 * other.0);
+    }
+    fn from_cost(cost: f64) -> Self {
+        return Counting((cost != 0.0) as u64);
+    }
+}
+
+// The child states and the target state of a production, independent of the direction of the automaton.
+fn child_and_target_states(grammar_type: &GrammarType, production: &Production) -> (Vec<String>, String) {
+    match grammar_type {
+        GrammarType::BottomUp => return (production.left_states.clone(), production.right_states[0].clone()),
+        GrammarType::TopDown => return (production.right_states.clone(), production.left_states[0].clone()),
+    }
+}
+
+// For every state q the sum over all runs on the tree that end in q. States without a run are left out.
+fn state_values<S: Semiring, T: fmt::Display>(
+    automaton_struct: &WeightedTreeAutomatonStruct,
+    tree: &Tree<T>,
+) -> HashMap<String, S> {
+    fn values_rec<S: Semiring, T: fmt::Display>(
+        automaton_struct: &WeightedTreeAutomatonStruct,
+        node: &Node<T>,
+    ) -> HashMap<String, S> {
+        let children: Vec<HashMap<String, S>> = node.iter().map(|child| values_rec(automaton_struct, child)).collect();
+        // The trees of top_down_to_bottom_up name symbols with several ranks "σ/k", both names are accepted.
+        let mut symbol = node.data().to_string();
+        if !automaton_struct.transition_structs.contains_key(&symbol) && symbol.contains('/') {
+            symbol = symbol.rsplit_once('/').unwrap().0.to_owned();
+        }
+        let mut values: HashMap<String, S> = HashMap::new();
+        for production in automaton_struct.transition_structs.get(&symbol).unwrap() {
+            let (child_states, target_state) = child_and_target_states(&automaton_struct.grammar_type, production);
+            if child_states.len() != children.len() {
+                continue;
+            }
+            let mut value = S::from_cost(production.cost);
+            for (child_state, child_values) in child_states.iter().zip(children.iter()) {
+                match child_values.get(child_state) {
+                    Some(child_value) => value = value.times(child_value),
+                    None => value = S::zero(),
+                }
+            }
+            let sum = values.entry(target_state).or_insert(S::zero());
+            *sum = sum.plus(&value);
+        }
+        return values;
+    }
+    return values_rec(automaton_struct, tree.root());
+}
+
+// Computes ||A||(t) in the semiring S for a bottom-up automaton, the final weights are the costs of the "!" productions.
+pub fn evaluate_tree<S: Semiring, T: fmt::Display>(automaton_struct: &WeightedTreeAutomatonStruct, tree: &Tree<T>) -> S {
+    if let GrammarType::TopDown = automaton_struct.grammar_type {
+        panic!("A top-down grammar has no final weights, use evaluate_top_down_tree with a root state.");
+    }
+    let values: HashMap<String, S> = state_values(automaton_struct, tree);
+    let mut result = S::zero();
+    for production in automaton_struct.transition_structs.get("!").unwrap() {
+        if let Some(value) = values.get(&production.left_states[0]) {
+            result = result.plus(&value.times(&S::from_cost(production.cost)));
+        }
+    }
+    debug!("Result: {result:?}");
+    return result;
+}
+
+// Computes ||A||(t) in the semiring S for a top-down grammar (for example read by read_top_down_automaton), whose
+// runs have to start in the root state.
+pub fn evaluate_top_down_tree<S: Semiring, T: fmt::Display>(
+    grammar: &WeightedTreeAutomatonStruct,
+    root_state: &str,
+    tree: &Tree<T>,
+) -> S {
+    let values: HashMap<String, S> = state_values(grammar, tree);
+    let result = values.get(root_state).cloned().unwrap_or(S::zero());
+    debug!("Result: {result:?}");
+    return result;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::computation::{compute_val_for_tree, compute_viterbi_run_for_tree};
+    use crate::data_structures;
+    use crate::parser;
+
+    #[test]
+    fn semirings_counter_example() {
+        let automaton_struct = parser::read_bottom_up_grammar("automata/bottom_up/counter_tree");
+        let automaton = data_structures::prod_vec_to_automaton(automaton_struct.clone());
+        let trees = [
+            Tree::<char>::from_tuple('a'),
+            Tree::<char>::from_tuple(('s', 'a', 'b')),
+            Tree::<char>::from_tuple(('s', ('s', 'a', 'a'), 'b')),
+            Tree::<char>::from_tuple(('s', 'b', ('s', 'b', 'b'))),
+        ];
+        for tree in trees {
+            let expected = compute_val_for_tree(automaton.clone(), tree.clone());
+            let Real(value) = evaluate_tree(&automaton_struct, &tree);
+            assert!((value - expected).abs() < 1e-12);
+            let Boolean(has_run) = evaluate_tree(&automaton_struct, &tree);
+            assert!(has_run);
+        }
+        // An accepting run is in q1 or q2 at exactly one leaf and in q3 everywhere else below it, so a tree with two
+        // leaves has two accepting runs (the run which is in q3 at the root has the final weight 0).
+        let Counting(runs) = evaluate_tree(&automaton_struct, &Tree::<char>::from_tuple(('s', 'a', 'b')));
+        assert_eq!(runs, 2);
+    }
+
+    #[test]
+    fn semirings_split_pcfg() {
+        let grammar = parser::read_top_down_automaton(
+            "automata/top_down/split_pcfg.grammar",
+            "automata/top_down/split_pcfg.lexicon",
+        );
+        let automaton = data_structures::prod_vec_to_top_down_automaton(&grammar, "ROOT_0");
+        let tree = Tree::<&str>::from_tuple(("ROOT", ("S", ("NP/2", "the", "dog"), ("VP", "saw", ("NP/1", "telescope")))));
+        let expected = compute_val_for_tree(automaton.clone(), tree.clone());
+        let Real(value) = evaluate_top_down_tree(&grammar, "ROOT_0", &tree);
+        assert!((value - expected).abs() < 1e-12);
+        let Log(log_value) = evaluate_top_down_tree(&grammar, "ROOT_0", &tree);
+        assert!((log_value - expected.ln()).abs() < 1e-12);
+        let Tropical(best) = evaluate_top_down_tree(&grammar, "ROOT_0", &tree);
+        let run = compute_viterbi_run_for_tree(&automaton, &tree).unwrap();
+        assert!((best - run.log_weight).abs() < 1e-12);
+        // Each of the two noun phrases is either NP_0 or NP_1.
+        let Counting(runs) = evaluate_top_down_tree(&grammar, "ROOT_0", &tree);
+        assert_eq!(runs, 4);
+        // The plain symbol names of the grammar work as well.
+        let tree = Tree::<&str>::from_tuple(("ROOT", ("S", ("NP", "the", "dog"), ("VP", "saw", ("NP", "telescope")))));
+        let Real(value) = evaluate_top_down_tree(&grammar, "ROOT_0", &tree);
+        assert!((value - expected).abs() < 1e-12);
+        let Boolean(has_run) = evaluate_top_down_tree(&grammar, "S_0", &tree);
+        assert!(!has_run);
+    }
+}
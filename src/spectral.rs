@@ -0,0 +1,257 @@
+use crate::data_structures::{
+    plug_into_context, ranked_alphabet_from_trees, GrammarType, WeightedTreeAutomatonMatrix, CONTEXT_HOLE,
+};
+use nalgebra::DMatrix;
+use std::collections::{BTreeMap, BTreeSet};
+use log::debug;
+use trees::{Node, Tree};
+
+type DynamicMatrix = DMatrix<f64>;
+
+// All singular values below eps are considered equal to 0 when used in numeric algorithms.
+const EPS: f64 = 0.00001;
+
+/*
+    Spectral learning of a weighted tree automaton from a block of its Hankel matrix.
+    Let T be a set of trees, C a set of contexts and H[t, c] = f(c[t]) the observed weights. With the truncated
+    SVD H ≈ U S V^T of rank n, the rows of F = H V = U S are the forward vectors of the trees in T and
+    F^+ = S^{-1} U^T is a left inverse of F. The learned automaton then has γ = F^+ h with h[t] = f(t) and μ_σ is
+    the least squares solution of F_σ μ_σ = H_σ V, where the rows of F_σ and H_σ belong to the tuples
+    (t_1, ..., t_k) of trees in T for which σ(t_1, ..., t_k) is in T as well:
+    F_σ[(t_1, ..., t_k)] = F[t_1] ⊗ ... ⊗ F[t_k] and H_σ[(t_1, ..., t_k), c] = f(c[σ(t_1, ..., t_k)]).
+    Taking all |T|^k tuples isn't feasible for real samples, but if T is closed under subtrees (as the trees of
+    basis_from_samples are), these are exactly the tuples that occur in the data.
+    If H has rank n, n is the dimension of a minimal automaton for f and the rows of every F_σ span the space of
+    dimension n^k, the learned automaton recognises f.
+*/
+
+// Learns an automaton with num_states states from the weights returned by the given function. The trees and
+// contexts define the rows and columns of the Hankel block.
+pub fn spectral_learning(
+    weight: &dyn Fn(&Tree<String>) -> f64,
+    ranked_alphabet: &BTreeMap<String, usize>,
+    trees: &[Tree<String>],
+    contexts: &[Tree<String>],
+    num_states: usize,
+) -> WeightedTreeAutomatonMatrix {
+    if trees.is_empty() || contexts.is_empty() {
+        panic!("Can't learn an automaton from an empty Hankel block, the sample contains no trees.");
+    }
+    let t_num = trees.len();
+    let mut h = DynamicMatrix::from_element(t_num, contexts.len(), 0.0);
+    let mut h_t = DynamicMatrix::from_element(t_num, 1, 0.0);
+    let mut row_index = BTreeMap::new();
+    for (row_num, tree) in trees.iter().enumerate() {
+        for (column_num, context) in contexts.iter().enumerate() {
+            h[(row_num, column_num)] = weight(&plug_into_context(context, tree));
+        }
+        h_t[(row_num, 0)] = weight(tree);
+        row_index.insert(tree, row_num);
+    }
+    debug!("H: {h}");
+
+    // The truncated SVD. nalgebra's SVD can be inaccurate for rank deficient matrices with more columns than rows,
+    // which is the usual shape of H, so V is computed from the eigendecomposition H^T H = V S^2 V^T instead.
+    let eigen = (h.transpose() * &h).symmetric_eigen();
+    let mut order: Vec<usize> = (0..eigen.eigenvalues.len()).collect();
+    order.sort_by(|&i, &j| eigen.eigenvalues[j].partial_cmp(&eigen.eigenvalues[i]).unwrap());
+    let rank = order.iter().filter(|&&i| eigen.eigenvalues[i].max(0.0).sqrt() > EPS).count();
+    let n = num_states.min(rank);
+    let v_columns: Vec<_> = order[..n].iter().map(|&i| eigen.eigenvectors.column(i)).collect();
+    let v = DynamicMatrix::from_columns(&v_columns);
+    let f = &h * &v;
+    // The columns of F are orthogonal with F^T F = S^2, so F^+ = S^{-2} F^T.
+    let s_squared_inverse = DynamicMatrix::from_fn(n, n, |i, j| {
+        if i == j {
+            return 1.0 / eigen.eigenvalues[order[i]];
+        }
+        return 0.0;
+    });
+    let f_pseudo_inverse = s_squared_inverse * f.transpose();
+    debug!("Learning an automaton with {n} states");
+
+    let mut transition_matrices = BTreeMap::new();
+    for (σ, rk_σ) in ranked_alphabet.iter() {
+        if σ == "!" {
+            continue;
+        }
+        // The tuples (t_1, ..., t_k) as indices of the rows of H, one for every tree σ(t_1, ..., t_k) in T.
+        // A leaf σ always gets the empty tuple, since its row is cheap to compute.
+        let mut tuples: Vec<Vec<usize>> = Vec::new();
+        for tree in trees.iter() {
+            if tree.root().data() != σ || tree.root().degree() != *rk_σ || *rk_σ == 0 {
+                continue;
+            }
+            let children: Option<Vec<usize>> =
+                tree.root().iter().map(|child| row_index.get(&child.deep_clone()).copied()).collect();
+            if let Some(children) = children {
+                tuples.push(children);
+            }
+        }
+        if *rk_σ == 0 {
+            tuples.push(Vec::new());
+        }
+        if tuples.is_empty() {
+            // σ never occurs at the root of a tree in T, so nothing is known about it and it gets weight 0.
+            debug!("{σ}: no tuples occur in the trees");
+            transition_matrices.insert(σ.clone(), DynamicMatrix::zeros(n.pow(*rk_σ as u32), n));
+            continue;
+        }
+
+        let mut f_σ = DynamicMatrix::from_element(tuples.len(), n.pow(*rk_σ as u32), 0.0);
+        let mut h_σ = DynamicMatrix::from_element(tuples.len(), contexts.len(), 0.0);
+        for (row_num, tuple) in tuples.iter().enumerate() {
+            let mut σ_tree = Tree::new(σ.clone());
+            let mut f_row = DynamicMatrix::from_element(1, 1, 1.0);
+            for index in tuple {
+                σ_tree.push_back(trees[*index].clone());
+                f_row = f_row.kronecker(&f.row(*index));
+            }
+            f_σ.set_row(row_num, &f_row.row(0));
+            for (column_num, context) in contexts.iter().enumerate() {
+                h_σ[(row_num, column_num)] = weight(&plug_into_context(context, &σ_tree));
+            }
+        }
+        debug!("{σ}: {} tuples occur in the trees", tuples.len());
+
+        let f_σ_pseudo_inverse = f_σ.pseudo_inverse(EPS).unwrap();
+        let μ_σ = f_σ_pseudo_inverse * h_σ * &v;
+        debug!("{σ}: {μ_σ}");
+        transition_matrices.insert(σ.clone(), μ_σ);
+    }
+    transition_matrices.insert("!".to_owned(), &f_pseudo_inverse * h_t);
+
+    let mut new_ranked_alphabet = ranked_alphabet.clone();
+    new_ranked_alphabet.insert("!".to_owned(), n);
+    return WeightedTreeAutomatonMatrix {
+        num_states: n,
+        grammar_type: GrammarType::BottomUp,
+        ranked_alphabet: new_ranked_alphabet,
+        transition_matrices,
+    };
+}
+
+// Turns a sample of trees into their relative frequencies, which are used as the observed weights.
+pub fn empirical_weights(samples: &[Tree<String>]) -> BTreeMap<Tree<String>, f64> {
+    let mut weights = BTreeMap::new();
+    for tree in samples {
+        *weights.entry(tree.clone()).or_insert(0.0) += 1.0 / samples.len() as f64;
+    }
+    return weights;
+}
+
+// Collects all subtrees and all contexts that occur in the samples. These can be used as rows and columns of the
+// Hankel block.
+pub fn basis_from_samples(samples: &[Tree<String>]) -> (Vec<Tree<String>>, Vec<Tree<String>>) {
+    // Returns all pairs (subtree, context) with context[subtree] = node.
+    fn split_rec(node: &Node<String>) -> Vec<(Tree<String>, Tree<String>)> {
+        let mut result = vec![(node.deep_clone(), Tree::new(CONTEXT_HOLE.to_owned()))];
+        let children: Vec<&Node<String>> = node.iter().collect();
+        for (position, child) in children.iter().enumerate() {
+            for (subtree, child_context) in split_rec(child) {
+                let mut context = Tree::new(node.data().clone());
+                for (other_position, other_child) in children.iter().enumerate() {
+                    if other_position == position {
+                        context.push_back(child_context.clone());
+                    } else {
+                        context.push_back(other_child.deep_clone());
+                    }
+                }
+                result.push((subtree, context));
+            }
+        }
+        return result;
+    }
+    let mut trees = BTreeSet::new();
+    let mut contexts = BTreeSet::new();
+    for sample in samples {
+        for (subtree, context) in split_rec(sample.root()) {
+            trees.insert(subtree);
+            contexts.insert(context);
+        }
+    }
+    return (trees.into_iter().collect(), contexts.into_iter().collect());
+}
+
+// Learns an automaton directly from a sample of trees, e.g. the trees of a treebank. The weight of a tree is its
+// relative frequency in the sample.
+pub fn spectral_learning_from_samples(
+    samples: &[Tree<String>],
+    num_states: usize,
+) -> WeightedTreeAutomatonMatrix {
+    let weights = empirical_weights(samples);
+    let (trees, contexts) = basis_from_samples(samples);
+    let ranked_alphabet = ranked_alphabet_from_trees(samples);
+    let weight = |tree: &Tree<String>| *weights.get(tree).unwrap_or(&0.0);
+    return spectral_learning(&weight, &ranked_alphabet, &trees, &contexts, num_states);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{computation, data_structures, parser};
+
+    fn string_tree(tree: &Tree<char>) -> Tree<String> {
+        fn convert_rec(node: &Node<char>) -> Tree<String> {
+            let mut new_tree = Tree::new(node.data().to_string());
+            for child in node.iter() {
+                new_tree.push_back(convert_rec(child));
+            }
+            return new_tree;
+        }
+        return convert_rec(tree.root());
+    }
+
+    #[test]
+    fn spectral_counter_example() {
+        let automaton = data_structures::prod_vec_to_automaton(
+            parser::read_bottom_up_grammar("automata/bottom_up/counter_tree"),
+        );
+        let weight = |tree: &Tree<String>| computation::compute_val_for_tree(automaton.clone(), tree.clone());
+        let samples: Vec<Tree<String>> = [
+            Tree::<char>::from_tuple(('s', 'a', 'b')),
+            Tree::<char>::from_tuple(('s', 'b', ('s', 'a', 'a'))),
+            // Only the tuples that occur in the samples are used for μ_s, so we also need (b, a) and (b, b).
+            Tree::<char>::from_tuple(('s', ('s', 'b', 'a'), ('s', 'b', 'b'))),
+        ]
+        .iter()
+        .map(string_tree)
+        .collect();
+        let (trees, contexts) = basis_from_samples(&samples);
+        let learned = spectral_learning(&weight, &automaton.ranked_alphabet, &trees, &contexts, 3);
+        assert_eq!(learned.num_states, 2);
+
+        for tree in [
+            Tree::<char>::from_tuple('a'),
+            Tree::<char>::from_tuple(('s', 'b', 'b')),
+            Tree::<char>::from_tuple(('s', ('s', 'b', 'b'), 'b')),
+            Tree::<char>::from_tuple(('s', ('s', 'a', 'b'), ('s', 'a', 'a'))),
+        ] {
+            let expected = computation::compute_val_for_tree(automaton.clone(), tree.clone());
+            let val = computation::compute_val_for_tree(learned.clone(), tree);
+            assert!((val - expected).abs() < EPS, "{val} != {expected}");
+        }
+    }
+
+    #[test]
+    fn spectral_unobserved_symbol() {
+        let automaton = data_structures::prod_vec_to_automaton(
+            parser::read_bottom_up_grammar("automata/bottom_up/counter_tree"),
+        );
+        let weight = |tree: &Tree<String>| computation::compute_val_for_tree(automaton.clone(), tree.clone());
+        let samples: Vec<Tree<String>> = [Tree::<char>::from_tuple(('s', 'a', 'b'))].iter().map(string_tree).collect();
+        let (trees, contexts) = basis_from_samples(&samples);
+        let mut ranked_alphabet = automaton.ranked_alphabet.clone();
+        ranked_alphabet.insert("c".to_owned(), 2);
+        let learned = spectral_learning(&weight, &ranked_alphabet, &trees, &contexts, 3);
+        assert!(learned.transition_matrices["c"].iter().all(|&x| x == 0.0));
+        let tree = string_tree(&Tree::<char>::from_tuple(('c', 'a', 'b')));
+        assert_eq!(computation::compute_val_for_tree(learned, tree), 0.0);
+    }
+
+    #[test]
+    #[should_panic(expected = "empty Hankel block")]
+    fn spectral_empty_sample() {
+        spectral_learning_from_samples(&[], 2);
+    }
+}
@@ -1,15 +1,41 @@
-use crate::{data_structures::WeightedTreeAutomatonMatrix, minimisation::next_tuple, minimisation::tzeng};
-use nalgebra::DMatrix;
+use crate::{data_structures::WeightedTreeAutomatonMatrix, data_structures::CONTEXT_HOLE, minimisation::next_tuple, minimisation::tzeng};
+use crate::parallel::parallel_map;
+use nalgebra::{DMatrix, DVector};
 use log::debug;
+use trees::Tree;
 
 type DynamicMatrix = DMatrix<f64>;
 
+// All singular values below eps are considered equal to 0 when used in numeric algorithms.
+const EPS: f64 = 0.00001;
+// Number of candidates for M that are computed at the same time.
+const BATCH_SIZE: usize = 1024;
+
+// F and the spanning trees are expected to be the output of step_i_forward_with_trees.
+// Returns the matrix B whose columns are a basis of the backward space, that is the span of all vectors μ(c) γ
+// for contexts c, where μ(c[t]) = μ(t) μ(c).
 pub fn step_ii_backward(
     automaton_matrix: &WeightedTreeAutomatonMatrix,
     f: &DynamicMatrix,
+    spanning_trees: &[Tree<String>],
 ) -> DynamicMatrix {
+    let (b, _) = step_ii_backward_with_contexts(automaton_matrix, f, spanning_trees);
+    return b;
+}
+
+// Same as step_ii_backward, but additionally returns the context c for every vector that was added to B.
+// The contexts are trees with exactly one leaf labelled CONTEXT_HOLE and are returned in the order in which
+// their vectors were added to B.
+pub fn step_ii_backward_with_contexts(
+    automaton_matrix: &WeightedTreeAutomatonMatrix,
+    f: &DynamicMatrix,
+    spanning_trees: &[Tree<String>],
+) -> (DynamicMatrix, Vec<Tree<String>>) {
+    automaton_matrix.assert_bottom_up("step_ii_backward");
     // Array in which to save Matricies M
     let mut m: Vec<DynamicMatrix> = Vec::new();
+    // The context σ(t_1, ..., □, ..., t_k) that belongs to each element of M.
+    let mut m_contexts: Vec<Tree<String>> = Vec::new();
 
     // We will use the identity matrix a couple of times in step II, so it makes sense to just create it once and then use references to this
     // instance later. 
@@ -21,8 +47,10 @@ pub fn step_ii_backward(
     // This part of the code cleans up F, that way we minimize the amount of computation we have to do later.
     let mut f_clean = DynamicMatrix::from_element(0, automaton_matrix.num_states,0.0,);
     let mut current_row_num = 0;
-    for f in f_index_vector {
+    let mut clean_trees: Vec<Tree<String>> = Vec::new();
+    for (row_num, f) in f_index_vector.enumerate() {
         if f.amax() != 0.0 {
+            clean_trees.push(spanning_trees[row_num].clone());
             f_clean = f_clean.clone().resize_vertically(current_row_num + 1, 0.0);
 
             for i in 0..f.column_iter().count() {
@@ -40,86 +68,139 @@ pub fn step_ii_backward(
     let t_num = f.row_iter().count();
 
 
-    for σ in automaton_matrix.ranked_alphabet.clone().into_iter() {
-        let rk_σ: &usize = automaton_matrix.ranked_alphabet.get(&σ.0).unwrap();
+    // The candidates for M are all (σ, identity_position, x). They are independent of each other, so the matrices
+    // are computed in parallel and then added to M in the order of the candidates, which keeps M reproducible.
+    let mut candidates: Vec<(&String, &DynamicMatrix, usize, Vec<usize>)> = Vec::new();
+    for (σ, rk_σ) in automaton_matrix.ranked_alphabet.iter() {
         // If rk_σ == 0, this means that the element isn't part of C^1_Σ,S so it gets skipped.
         // We also deal with the final weight vector later, so we skip it here.
-        if *rk_σ == 0 || σ.0 == "!" {
+        if *rk_σ == 0 || σ == "!" {
+            continue;
+        }
+
+        // Without any trees t there are no contexts with more than one child.
+        if t_num == 0 && *rk_σ > 1 {
             continue;
         }
 
         // Get the transition matrix for σ.
-        let μ_σ = automaton_matrix.transition_matrices.get(&σ.0).unwrap();
+        let μ_σ = automaton_matrix.transition_matrices.get(σ).unwrap();
         // Since the kronecker product isn't commutative we need to compute all possible positions of the identity matrix (square).
         // Its current position is saved in this variable. 
-
         for identity_position in 0..*rk_σ {
-            debug!("σ: {} - rk_σ: {rk_σ} - identity_position: {identity_position}", σ.0);
-            debug!("Computing (t_1 ⊗ identity ⊗ rk_σ) * μ_σ where the position of identity can also be 1 or rk_σ and rk_σ is >= 1");
-            // x is a vector which contains the current combination of elements c ∈ C^1_Σ,S that we want to use to compute the current m.
+            // x contains the indices of the trees t_1, ..., t_k (without the position of the identity matrix) that we
+            // want to use to compute the current m. next_tuple counts from 1, so all indices are shifted by one.
             // In each loop the vector is changed and another permutation is chosen until all permutations are done. See the next_tuple() function.
-            let mut x: Vec<usize> = vec![0; *rk_σ];
+            let mut x: Vec<usize> = vec![1; *rk_σ - 1];
             // This loop iterates over all possible permutations for this specific identity position and breaks afterwards.
             loop {
-                let mut cur_position = 0;
-                // New element is initialised as a [1] matrix. That way we can compute new_element.kronecker(val) without changing val, which makes the loop clean.
-                let mut new_element_m = DMatrix::from_element(1, 1, 1.0);
-                
-                debug!("x: {x:?}");
-                for index in &x {
-                    let f_index_vector = f.select_rows([*index].iter());
-                    debug!("Vec: {}", f_index_vector);
-                    if f_index_vector.amax() == 0.0 {
-                        cur_position = 0;
-                        break;
-                    }
-                    debug!("Cur Index: {} - cur_position: {cur_position} - identity_position: {identity_position}", *index);
-                    if cur_position == identity_position {
-                        new_element_m = new_element_m.kronecker(&identity);
-                        debug!("Kroenecker with identity");
-                    } else {
-                        debug!("Kroenecker with: {f_index_vector}");
-                        new_element_m = new_element_m.kronecker(&f_index_vector);
-                    }
-                    debug!("Done");
-
-                    cur_position += 1;
-                }
-                debug!("cur {cur_position} new_element_m {new_element_m}");
-                if cur_position != 0{
-                    debug!("μ_σ: {μ_σ}");
-                    new_element_m = new_element_m * μ_σ;
-                    debug!("New element for M: {new_element_m}");
-                        if new_element_m.amax() != 0.0 {
-                        if m.contains(&new_element_m) == false && (new_element_m != identity){
-                            m.push(new_element_m.clone());
-                        }
-                    }
-                }
-                
-
+                candidates.push((σ, μ_σ, identity_position, x.iter().map(|index| index - 1).collect()));
                 // Check if there are still possible tuples to compute a new element of M for.
-                if let Some(temp_x) = next_tuple(x, t_num - 1,  false) {
+                if let Some(temp_x) = next_tuple(x, t_num, false) {
                     x = temp_x;
                 } else {
-                    debug!("Finished tuple");
                     break;
                 }
             }
         }
     }
+    debug!("Computing (t_1 ⊗ identity ⊗ rk_σ) * μ_σ for {} candidates", candidates.len());
+
+    // Tzeng's algorithm only needs the space spanned by M: if M = Σ c_j M_j, then M v = Σ c_j M_j v already lies in
+    // the span of the vectors M_j v. So we only keep linearly independent elements, which bounds M by n^2 matrices
+    // instead of one matrix per candidate. m_basis is an orthonormal basis of the flattened elements of M that is
+    // used for the independence check. The candidates are computed in batches, so that they never all need to be
+    // kept in memory at the same time.
+    let max_m_len = automaton_matrix.num_states.pow(2);
+    let mut m_basis: Vec<DVector<f64>> = Vec::new();
+    for batch in candidates.chunks(BATCH_SIZE) {
+        if m.len() >= max_m_len {
+            break;
+        }
+        let new_elements = parallel_map(batch, |(_, μ_σ, identity_position, x)| {
+            // New element is initialised as a [1] matrix. That way we can compute new_element.kronecker(val) without changing val, which makes the loop clean.
+            let mut new_element_m = DMatrix::from_element(1, 1, 1.0);
+            for (cur_position, index) in x.iter().enumerate() {
+                if cur_position == *identity_position {
+                    new_element_m = new_element_m.kronecker(&identity);
+                }
+                new_element_m = new_element_m.kronecker(&f.select_rows([*index].iter()));
+            }
+            if x.len() == *identity_position {
+                new_element_m = new_element_m.kronecker(&identity);
+            }
+            return new_element_m * *μ_σ;
+        });
+
+        for ((σ, _, identity_position, x), new_element_m) in batch.iter().zip(new_elements) {
+            debug!("New element for M: {new_element_m}");
+            if new_element_m != identity && add_if_independent(&mut m_basis, &new_element_m) {
+                let mut context = Tree::new((*σ).clone());
+                for (position, index) in x.iter().enumerate() {
+                    if position == *identity_position {
+                        context.push_back(Tree::new(CONTEXT_HOLE.to_owned()));
+                    }
+                    context.push_back(clean_trees[*index].clone());
+                }
+                if x.len() == *identity_position {
+                    context.push_back(Tree::new(CONTEXT_HOLE.to_owned()));
+                }
+                m.push(new_element_m);
+                m_contexts.push(context);
+            }
+        }
+    }
     debug!("M with len: {}", m.len());
     for element in &m {
         debug!("{element} ∈ M");
     }
-    let mut γ = automaton_matrix.transition_matrices.get("!").unwrap().clone();
-    let mut b = DynamicMatrix::from_element(automaton_matrix.num_states,automaton_matrix.num_states,0.0,);
-    // γ ∈ V by definition of V.
-    tzeng(
-        &mut γ,
-        &mut b,
-        &m,
-    );
-    return b;
+    let γ = automaton_matrix.transition_matrices.get("!").unwrap();
+    // B is the column space of the backward vectors, it starts without any columns.
+    let mut b = DynamicMatrix::from_element(automaton_matrix.num_states, 0, 0.0);
+    // γ ∈ V by definition of V. Its context is the empty context □.
+    let contexts = tzeng(γ, &mut b, &m, &m_contexts);
+    return (b, contexts);
 }
 
+
+// Adds the flattened matrix to the orthonormal basis if it is linearly independent of it (relative to its norm)
+// and returns whether it was added. The projection is done twice, which keeps the basis orthonormal in spite of
+// rounding errors (Gram-Schmidt with reorthogonalisation).
+fn add_if_independent(basis: &mut Vec<DVector<f64>>, matrix: &DynamicMatrix) -> bool {
+    let vector = DVector::from_column_slice(matrix.as_slice());
+    let norm = vector.norm();
+    if norm == 0.0 {
+        return false;
+    }
+    let mut residual = vector;
+    for _ in 0..2 {
+        for basis_vector in basis.iter() {
+            let projection = basis_vector.dot(&residual);
+            residual -= basis_vector * projection;
+        }
+    }
+    let residual_norm = residual.norm();
+    if residual_norm <= EPS * norm {
+        return false;
+    }
+    basis.push(residual / residual_norm);
+    return true;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_independent_elements_of_m() {
+        let a = DynamicMatrix::from_row_slice(2, 2, &[1.0, 2.0, 0.0, 1.0]);
+        let b = DynamicMatrix::from_row_slice(2, 2, &[0.0, 1.0, 1.0, 0.0]);
+        let mut basis = Vec::new();
+        assert!(add_if_independent(&mut basis, &a));
+        assert!(!add_if_independent(&mut basis, &(&a * 2.0)));
+        assert!(add_if_independent(&mut basis, &b));
+        assert!(!add_if_independent(&mut basis, &(&a - &b * 3.0)));
+        assert!(!add_if_independent(&mut basis, &DynamicMatrix::zeros(2, 2)));
+        assert_eq!(basis.len(), 2);
+    }
+}
@@ -1,6 +1,6 @@
 use crate::data_structures::WeightedTreeAutomatonMatrix;
 use nalgebra::DMatrix;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use log::debug;
 
 type DynamicMatrix = DMatrix<f64>;
@@ -10,39 +10,45 @@ const EPS: f64 = 0.00001;
 
 
 
+// F contains the vectors μ(t) of the forward space as rows and B the vectors of the backward space as columns.
+// We pick rows of F until the rows F̃B span the row space of FB, their number is the minimal number of states.
+// The minimal automaton then is given by γ̃ = F̃γ and by the solution μ̃_σ of μ̃_σ F̃B = F̃^{⊗k} μ_σ B, which
+// exists since the rows of F̃B are linearly independent.
 pub fn step_iii_solve(
     automaton_matrix: &WeightedTreeAutomatonMatrix,
     f: &DynamicMatrix,
     b: &DynamicMatrix,
 ) -> WeightedTreeAutomatonMatrix {
-    let mut new_transition_matrices: HashMap<String, DynamicMatrix> = HashMap::new();
+    automaton_matrix.assert_bottom_up("step_iii_solve");
+    let mut new_transition_matrices: BTreeMap<String, DynamicMatrix> = BTreeMap::new();
     let mut new_f = DynamicMatrix::from_element(0, automaton_matrix.num_states, 0.0);
+    let mut new_fb = DynamicMatrix::from_element(0, b.ncols(), 0.0);
     // Ranks are saved in variables instead of being computed on the fly since there is no defined rank for [] in nalgebra.
     // This way we start by 0 and keep the for loop clean.
     let mut old_rank = 0;
-    let mut current_row_num = 0;
-    for row_num in 0..(f.row_iter().count()) {
-        debug!("New F = {new_f} with row_num: {row_num}");
-        let mut temp_new_f = new_f.clone().resize_vertically(current_row_num + 1, 0.0);
-        let cur_row = f.clone().row(row_num)*b;
-        for index in 0..automaton_matrix.num_states {
-            temp_new_f[(current_row_num, index)] = cur_row[index];
-        }
-        let new_rank = temp_new_f.rank(EPS);
-        // If new_rank is bigger than the previous one that implies that the added row is
-        // linearly independent from the the other rows in new_f.
-        // As such we save temp_new_f and edit the other values to reflect this new new_f.
-        if new_rank > old_rank {
-            old_rank = new_rank;
-            current_row_num += 1;
-            new_f = temp_new_f;
+    if b.ncols() > 0 {
+        for row_num in 0..f.nrows() {
+            debug!("New FB = {new_fb} with row_num: {row_num}");
+            let current_row_num = new_fb.nrows();
+            let mut temp_new_fb = new_fb.clone().insert_row(current_row_num, 0.0);
+            temp_new_fb.set_row(current_row_num, &(f.row(row_num) * b));
+            let new_rank = temp_new_fb.rank(EPS);
+            // If new_rank is bigger than the previous one that implies that the added row is
+            // linearly independent from the the other rows in new_fb.
+            // As such we save the row of F and of FB.
+            if new_rank > old_rank {
+                old_rank = new_rank;
+                new_fb = temp_new_fb;
+                new_f = new_f.insert_row(current_row_num, 0.0);
+                new_f.set_row(current_row_num, &f.row(row_num));
+            }
         }
     }
     debug!("B: {b}");
     debug!("F: {f}");
     debug!("Tilde F: {new_f}");
 
-    // A new γ is easily computed: γ * new_F
+    // A new γ is easily computed: new_F * γ
     let new_gamma = &new_f * automaton_matrix.transition_matrices.get("!").unwrap();
     debug!("New Gamma: {new_gamma}");
     new_transition_matrices.insert("!".to_string(), new_gamma);
@@ -57,8 +63,14 @@ pub fn step_iii_solve(
     f_kron_k.insert(0, identity);
     // The 1-fold-kronecker product is defined as new_f.
     f_kron_k.insert(1, new_f.clone());
-    // Compute the higest rk(σ).
-    let max_rk_μ_σ = automaton_matrix.ranked_alphabet.clone().into_values().max().unwrap();
+    // Compute the higest rk(σ). The rank of the final weight vector is the number of states, so it is left out.
+    let max_rk_μ_σ = automaton_matrix
+        .ranked_alphabet
+        .iter()
+        .filter(|(σ, _)| *σ != "!")
+        .map(|(_, rk_σ)| *rk_σ)
+        .max()
+        .unwrap_or(0);
     // Fill the hashmap with all needed kronecker products.
     for cur_k in 2..(max_rk_μ_σ + 1) {
         let new_k_fold = f_kron_k.get(&(cur_k - 1)).unwrap().kronecker(&new_f);
@@ -67,9 +79,10 @@ pub fn step_iii_solve(
     }
 
     // Count the rows to get the new number of states!
-    let new_n = (&new_f).row_iter().count();
-    // Creating a decomposition of FB.
-    let new_f_decomp = (&new_f).clone().transpose().svd(true, true);
+    let new_n = new_f.nrows();
+    // Creating a decomposition of (F̃B)^T, the equation μ̃_σ F̃B = right side is solved for all rows at once by
+    // (F̃B)^T μ̃_σ^T = right side^T. Without states there is nothing to solve (and nalgebra can't decompose [] anyways).
+    let new_fb_decomp = (new_n > 0).then(|| new_fb.transpose().svd(true, true));
 
     // Computing the new transition matrices for each symbol.
     for μ_σ in automaton_matrix.transition_matrices.iter() {
@@ -81,23 +94,14 @@ pub fn step_iii_solve(
         let old_μ_σ =
             automaton_matrix.transition_matrices.get(μ_σ.0).unwrap();
         debug!("rk_σ: {rk_σ} old_μ_σ: {old_μ_σ} b: {b}");
-        let right_side = f_kron_k.get(rk_σ).unwrap() * old_μ_σ;
-        debug!("Left side: {}", (&new_f).transpose());
+        let right_side = f_kron_k.get(rk_σ).unwrap() * old_μ_σ * b;
         debug!("Right side Full: {right_side}");
 
         // Create a new, empty Matrix of the right dimensions for μ_σ.
         // The dimensions are known, since they are based on new_n and rk_σ.
         let mut new_μ_σ = DynamicMatrix::from_element(new_n.pow(*rk_σ as u32), new_n, 0.0);
-        let mut current_row_index = 0;
-        for row in right_side.row_iter() {
-            debug!("Right side: {}", row.transpose());
-            let new_row = new_f_decomp.solve(&row.transpose(), EPS).unwrap();
-            debug!("The new row: {new_row}");
-            for index in 0..new_n {
-                debug!("Writing into {current_row_index}, {index}");
-                new_μ_σ[(current_row_index, index)] = new_row[(index, 0)];
-            }
-            current_row_index += 1;
+        if let Some(new_fb_decomp) = &new_fb_decomp {
+            new_μ_σ = new_fb_decomp.solve(&right_side.transpose(), EPS).unwrap().transpose();
         }
         debug!("New version: {new_μ_σ}");
         new_transition_matrices.insert(μ_σ.0.clone(), new_μ_σ);
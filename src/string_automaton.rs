@@ -0,0 +1,142 @@
+use crate::data_structures::{GrammarType, WeightedTreeAutomatonMatrix};
+use crate::minimisation::minimize_automaton;
+use nalgebra::DMatrix;
+use log::debug;
+use std::collections::BTreeMap;
+use std::fmt;
+use trees::Tree;
+
+type DynamicMatrix = DMatrix<f64>;
+
+/*
+    Weighted string automata are a special case of weighted tree automata: a word w_1 ... w_m is the monadic tree
+    w_m(...(w_1(#))...) over the letters (rank 1) and the end marker # (rank 0). The bottom-up automaton with
+    μ_# = α, μ_a = M_a and γ = β then computes μ(t) γ = α M_w_1 ... M_w_m β, which is the weight of the word.
+    So minimize_automaton also minimises string automata (Schützenberger's algorithm is the special case of
+    this construction for monadic trees) and the result can be read back as a string automaton.
+*/
+
+// The leaf symbol that marks the start of a word in the tree representation.
+pub const WORD_END: &str = "#";
+
+#[derive(Debug, Clone)]
+pub struct WeightedStringAutomaton {
+    pub num_states: usize,
+    // α with the shape 1 × n.
+    pub initial_weights: DynamicMatrix,
+    // M_a with the shape n × n for every letter a.
+    pub transition_matrices: BTreeMap<String, DynamicMatrix>,
+    // β with the shape n × 1.
+    pub final_weights: DynamicMatrix,
+}
+
+impl WeightedStringAutomaton {
+    // Computes α M_w_1 ... M_w_m β. A letter outside the alphabet has no transitions, so words containing one
+    // have weight 0.
+    pub fn weight(&self, word: &[String]) -> f64 {
+        let mut vector = self.initial_weights.clone();
+        for letter in word {
+            match self.transition_matrices.get(letter) {
+                Some(m_letter) => vector *= m_letter,
+                None => return 0.0,
+            }
+        }
+        return (vector * &self.final_weights)[(0, 0)];
+    }
+
+    pub fn to_tree_automaton(&self) -> WeightedTreeAutomatonMatrix {
+        let mut ranked_alphabet = BTreeMap::new();
+        let mut transition_matrices = BTreeMap::new();
+        for (letter, matrix) in self.transition_matrices.iter() {
+            ranked_alphabet.insert(letter.clone(), 1);
+            transition_matrices.insert(letter.clone(), matrix.clone());
+        }
+        ranked_alphabet.insert(WORD_END.to_owned(), 0);
+        transition_matrices.insert(WORD_END.to_owned(), self.initial_weights.clone());
+        ranked_alphabet.insert("!".to_owned(), self.num_states);
+        transition_matrices.insert("!".to_owned(), self.final_weights.clone());
+        return WeightedTreeAutomatonMatrix {
+            num_states: self.num_states,
+            grammar_type: GrammarType::BottomUp,
+            ranked_alphabet,
+            transition_matrices,
+        };
+    }
+
+    // The inverse of to_tree_automaton, panics if the automaton has other symbols than WORD_END and letters of rank 1.
+    pub fn from_tree_automaton(automaton_matrix: &WeightedTreeAutomatonMatrix) -> WeightedStringAutomaton {
+        let automaton_matrix = automaton_matrix.to_bottom_up();
+        let mut transition_matrices = BTreeMap::new();
+        for (σ, rk_σ) in automaton_matrix.ranked_alphabet.iter() {
+            if σ == "!" || σ == WORD_END {
+                continue;
+            }
+            if *rk_σ != 1 {
+                panic!("The symbol {σ} has rank {rk_σ}, but a string automaton only has letters of rank 1.");
+            }
+            transition_matrices.insert(σ.clone(), automaton_matrix.transition_matrices.get(σ).unwrap().clone());
+        }
+        return WeightedStringAutomaton {
+            num_states: automaton_matrix.num_states,
+            initial_weights: automaton_matrix.transition_matrices.get(WORD_END).unwrap().clone(),
+            transition_matrices,
+            final_weights: automaton_matrix.transition_matrices.get("!").unwrap().clone(),
+        };
+    }
+}
+
+impl fmt::Display for WeightedStringAutomaton {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut result = format!("Number of States: {} \nInitial Weights: {}", self.num_states, self.initial_weights);
+        result.push_str("\nTransition Matrices:");
+        for (letter, matrix) in self.transition_matrices.iter() {
+            result.push_str(&format!("\n{letter}: {matrix}"));
+        }
+        result.push_str(&format!("\nFinal Weights: {}", self.final_weights));
+        write!(f, "{}", result)
+    }
+}
+
+// Turns the word w_1 ... w_m into the tree w_m(...(w_1(#))...).
+pub fn word_to_tree(word: &[String]) -> Tree<String> {
+    let mut tree = Tree::new(WORD_END.to_owned());
+    for letter in word {
+        let mut new_tree = Tree::new(letter.clone());
+        new_tree.push_back(tree);
+        tree = new_tree;
+    }
+    return tree;
+}
+
+// Minimises a string automaton with the minimisation of tree automata.
+pub fn minimise_string_automaton(automaton: &WeightedStringAutomaton) -> WeightedStringAutomaton {
+    let minimised = minimize_automaton(automaton.to_tree_automaton());
+    debug!("Minimised string automaton: {minimised}");
+    return WeightedStringAutomaton::from_tree_automaton(&minimised);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::computation::compute_val_for_tree;
+    use crate::parser;
+
+    #[test]
+    fn minimise_count_a() {
+        let automaton = parser::read_string_automaton("automata/string/count_a");
+        let minimised = minimise_string_automaton(&automaton);
+        assert_eq!(automaton.num_states, 3);
+        assert_eq!(minimised.num_states, 2);
+        let tree_automaton = automaton.to_tree_automaton();
+        // The automaton counts the a's of a word.
+        for word in parser::read_words("automata/string/count_a.words") {
+            let expected = word.iter().filter(|letter| *letter == "a").count() as f64;
+            assert!((automaton.weight(&word) - expected).abs() < 1e-9);
+            assert!((minimised.weight(&word) - expected).abs() < 1e-9);
+            assert!((compute_val_for_tree(tree_automaton.clone(), word_to_tree(&word)) - expected).abs() < 1e-9);
+        }
+        let unknown_word = vec!["a".to_owned(), "c".to_owned()];
+        assert_eq!(automaton.weight(&unknown_word), 0.0);
+        assert_eq!(minimised.weight(&unknown_word), 0.0);
+    }
+}
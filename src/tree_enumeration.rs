@@ -0,0 +1,158 @@
+use crate::computation::compute_val_for_tree;
+use crate::data_structures::WeightedTreeAutomatonMatrix;
+use std::collections::BTreeMap;
+use log::debug;
+use trees::Tree;
+
+/*
+    Enumeration of all trees over a ranked alphabet in order of their size (number of nodes).
+    The trees of size m are built from the trees of smaller sizes: for every symbol σ of rank k and every way to
+    write m - 1 = m_1 + ... + m_k with m_i >= 1, we combine all trees of the sizes m_1, ..., m_k.
+    Within one size the trees are ordered by the root symbol, then by the sizes of the children and then by
+    the position of the children in the previous lists, so the order is the same every run.
+*/
+
+pub struct TreeEnumerator {
+    ranked_alphabet: BTreeMap<String, usize>,
+    max_size: usize,
+    // trees_by_size[m] contains all trees with m nodes, trees_by_size[0] is always empty.
+    trees_by_size: Vec<Vec<Tree<String>>>,
+    // Position of the next tree that is returned by the iterator.
+    current_size: usize,
+    current_index: usize,
+}
+
+impl TreeEnumerator {
+    // Creates an iterator over all trees with at most max_size nodes. The final weight vector "!" is ignored.
+    pub fn new(ranked_alphabet: &BTreeMap<String, usize>, max_size: usize) -> TreeEnumerator {
+        let mut ranked_alphabet = ranked_alphabet.clone();
+        ranked_alphabet.remove("!");
+        return TreeEnumerator {
+            ranked_alphabet,
+            max_size,
+            trees_by_size: vec![Vec::new()],
+            current_size: 0,
+            current_index: 0,
+        };
+    }
+
+    // Returns all ways to write total as an ordered sum of k positive numbers.
+    fn compositions(total: usize, k: usize) -> Vec<Vec<usize>> {
+        if k == 0 {
+            if total == 0 {
+                return vec![vec![]];
+            }
+            return vec![];
+        }
+        let mut result = Vec::new();
+        for first in 1..(total + 1) {
+            for mut rest in TreeEnumerator::compositions(total - first, k - 1) {
+                rest.insert(0, first);
+                result.push(rest);
+            }
+        }
+        return result;
+    }
+
+    // Computes all trees with m nodes, assuming that all smaller sizes are already known.
+    fn build_size(&mut self, m: usize) {
+        let mut new_trees = Vec::new();
+        for (σ, rk_σ) in self.ranked_alphabet.iter() {
+            for sizes in TreeEnumerator::compositions(m - 1, *rk_σ) {
+                // Every partial tree contains σ and the children for the first positions of sizes.
+                let mut partial_trees = vec![Tree::new(σ.clone())];
+                for size in sizes {
+                    let mut extended_trees = Vec::new();
+                    for partial_tree in partial_trees.iter() {
+                        for child in self.trees_by_size[size].iter() {
+                            let mut extended_tree = partial_tree.clone();
+                            extended_tree.push_back(child.clone());
+                            extended_trees.push(extended_tree);
+                        }
+                    }
+                    partial_trees = extended_trees;
+                }
+                new_trees.append(&mut partial_trees);
+            }
+        }
+        debug!("There are {} trees of size {m}", new_trees.len());
+        self.trees_by_size.push(new_trees);
+    }
+}
+
+impl Iterator for TreeEnumerator {
+    type Item = Tree<String>;
+
+    fn next(&mut self) -> Option<Tree<String>> {
+        while self.current_index >= self.trees_by_size[self.current_size].len() {
+            if self.current_size >= self.max_size {
+                return None;
+            }
+            self.current_size += 1;
+            self.current_index = 0;
+            if self.trees_by_size.len() <= self.current_size {
+                self.build_size(self.current_size);
+            }
+        }
+        self.current_index += 1;
+        return Some(self.trees_by_size[self.current_size][self.current_index - 1].clone());
+    }
+}
+
+// Prints ||A||(t) and ||Ã||(t) for all trees up to max_size side by side and flags every tree where the two values
+// differ by more than the tolerance. For weights larger than 1 the tolerance is relative to the weight, since the
+// rounding errors grow with the weights. Returns the number of flagged trees.
+pub fn print_comparison_table(
+    automaton: &WeightedTreeAutomatonMatrix,
+    minimised_automaton: &WeightedTreeAutomatonMatrix,
+    max_size: usize,
+    tolerance: f64,
+) -> usize {
+    let mut mismatches = 0;
+    println!("{:<40} {:>20} {:>20} {:>12}", "Tree", "||A||(t)", "||Ã||(t)", "Difference");
+    for tree in TreeEnumerator::new(&automaton.ranked_alphabet, max_size) {
+        let val = compute_val_for_tree(automaton.clone(), tree.clone());
+        let val_minimised = compute_val_for_tree(minimised_automaton.clone(), tree.clone());
+        let difference = (val - val_minimised).abs();
+        let mut flag = "";
+        if difference > tolerance * val.abs().max(1.0) {
+            flag = "  <- MISMATCH";
+            mismatches += 1;
+        }
+        println!(
+            "{:<40} {:>20.6} {:>20.6} {:>12.6}{flag}",
+            tree.to_string(),
+            val,
+            val_minimised,
+            difference
+        );
+    }
+    println!("{mismatches} tree(s) differ by more than {tolerance}.");
+    return mismatches;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn enumerate_small_trees() {
+        let mut ranked_alphabet = BTreeMap::new();
+        ranked_alphabet.insert("a".to_owned(), 0);
+        ranked_alphabet.insert("b".to_owned(), 0);
+        ranked_alphabet.insert("g".to_owned(), 1);
+        ranked_alphabet.insert("s".to_owned(), 2);
+        ranked_alphabet.insert("!".to_owned(), 3);
+        let trees: Vec<String> = TreeEnumerator::new(&ranked_alphabet, 3)
+            .map(|tree| tree.to_string())
+            .collect();
+        // Size 1: a, b. Size 2: g(a), g(b). Size 3: g(g(a)), g(g(b)) and the four trees s(x, y).
+        assert_eq!(trees.len(), 10);
+        assert_eq!(trees[0], "a");
+        assert_eq!(trees[2], "g( a )");
+        assert_eq!(trees[9], "s( b b )");
+        // Size 4: g applied to the 6 trees of size 3 and s with children of sizes (1, 2) or (2, 1).
+        // Size 5: g applied to the 14 trees of size 4 and s with children of sizes (1, 3), (2, 2) or (3, 1).
+        assert_eq!(TreeEnumerator::new(&ranked_alphabet, 5).count(), 2 + 2 + 6 + (6 + 8) + (14 + 12 + 4 + 12));
+    }
+}